@@ -0,0 +1,164 @@
+// Assign blocks to fixed centers so every district gets (nearly) the same
+// population, as a min cost flow:
+//
+//   source -> block (population) -> center (squared distance) -> sink (target)
+//
+// A block may be split between centers by the flow; it goes to whichever got
+// most of it, so districts are only balanced to within a block or so.
+//
+// The flow graph has a vertex per block, and every augmenting path is a
+// Dijkstra that scans all of them for the next closest (O(V^2), see
+// mcf_search), with up to about one path per block. That's fine for a county
+// or a few thousand blocks but takes a long while on a whole state, so
+// progress is reported as it goes and it can be stopped part way, keeping
+// what was routed so far.
+
+use serde::{Serialize, Deserialize};
+use crate::eb_tech::flow::FlowGraph;
+use crate::solver::{assign_to_centers, BlockEntry, Center};
+
+// Squared distances are scaled to this many units per district spacing before
+// being rounded for the flow, which only deals in integers
+const COST_SCALE : f64 = 1000.;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BalanceProgress {
+  pub augmentations: usize,
+  // population routed to a center so far, out of total_population
+  pub routed_population: u64,
+  pub total_population: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Balanced {
+  pub assignment: Vec<u32>,
+  pub populations: Vec<u64>,
+  pub progress: BalanceProgress,
+  // false if on_progress stopped it early
+  pub completed: bool,
+}
+
+// on_progress is called every `every` augmentations (and once at the end).
+// Returning false stops it. Blocks the flow hasn't reached by then go to their
+// nearest center, as assign_to_centers would have them.
+pub fn balance_with<F>(blocks : &[BlockEntry], centers : &[Center], every : usize, mut on_progress : F) -> Balanced
+where F : FnMut(&BalanceProgress) -> bool {
+  let k = centers.len();
+  let n = blocks.len();
+  let total_population : u64 = blocks.iter().map(|b| b.population as u64).sum();
+  let target = (total_population + k as u64 - 1) / k.max(1) as u64;
+
+  // keep the costs in a sensible integer range whatever the units
+  let (min, max) = blocks.iter().fold(
+    ((f64::INFINITY, f64::INFINITY), (f64::NEG_INFINITY, f64::NEG_INFINITY)),
+    |(min, max), b| ((min.0.min(b.coords.0), min.1.min(b.coords.1)), (max.0.max(b.coords.0), max.1.max(b.coords.1)))
+  );
+  let area = if n == 0 { 1. } else { ((max.0 - min.0) * (max.1 - min.1)).max(1.) };
+  let spacing_sq = area / k.max(1) as f64;
+
+  // vertices: source, blocks, centers, sink
+  let source = 0;
+  let sink = n + k + 1;
+  let mut graph = FlowGraph::new(n + k + 2, n * (k + 1) + k);
+  for (i, b) in blocks.iter().enumerate() {
+    if b.population == 0 { continue; }
+    graph.add_edge(source, 1 + i, b.population as i64, 0, 0);
+    for (j, c) in centers.iter().enumerate() {
+      let dx = b.coords.0 - c.coords.0;
+      let dy = b.coords.1 - c.coords.1;
+      let cost = ((dx * dx + dy * dy) / spacing_sq * COST_SCALE).round() as i64;
+      graph.add_edge(1 + i, 1 + n + j, b.population as i64, 0, cost);
+    }
+  }
+  for j in 0..k {
+    graph.add_edge(1 + n + j, sink, target as i64, 0, 0);
+  }
+
+  let every = every.max(1);
+  let mut progress = BalanceProgress { total_population, ..Default::default() };
+  let mut completed = true;
+  let (_, _, flow) = graph.mcf_with(source, sink, |augmentations, _, routed| {
+    progress.augmentations = augmentations;
+    progress.routed_population = routed as u64;
+    if augmentations % every == 0 && !on_progress(&progress) {
+      completed = false;
+      return false;
+    }
+    true
+  });
+
+  // start from the nearest centers, then move every block the flow reached
+  let mut assignment = vec![0; n];
+  let mut populations = vec![0; k];
+  assign_to_centers(blocks, centers, &mut assignment, &mut populations);
+
+  // the block -> center edges come right after each block's source edge, in
+  // pairs (edge and its reverse)
+  let mut e = 0;
+  for (i, b) in blocks.iter().enumerate() {
+    if b.population == 0 { continue; }
+    let first = e + 2;
+    e += 2 * (k + 1);
+    let best = (0..k).max_by_key(|&j| flow[first + 2 * j]);
+    if let Some(j) = best.filter(|&j| flow[first + 2 * j] > 0) {
+      populations[assignment[i] as usize] -= b.population as u64;
+      populations[j] += b.population as u64;
+      assignment[i] = j as u32;
+    }
+  }
+
+  if completed {
+    on_progress(&progress);
+  }
+
+  Balanced { assignment, populations, progress, completed }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn line() -> Vec<BlockEntry> {
+    // six equal blocks in a row
+    (0..6).map(|i| BlockEntry { coords: (i as f64, 0.), population: 10 }).collect()
+  }
+
+  #[test]
+  fn test_balance_evens_out() {
+    let blocks = line();
+    // the nearest center would take five of the six blocks
+    let centers = vec![
+      Center { coords: (1., 0.), weight: 0. },
+      Center { coords: (6., 0.), weight: 0. },
+    ];
+    let mut calls = 0;
+    let balanced = balance_with(&blocks, &centers, 1, |_| { calls += 1; true });
+    assert!(balanced.completed);
+    assert_eq!(balanced.populations, vec![30, 30]);
+    assert_eq!(balanced.assignment, vec![0, 0, 0, 1, 1, 1]);
+    assert_eq!(balanced.progress.routed_population, 60);
+    assert!(calls > 1);
+  }
+
+  #[test]
+  fn test_balance_stops_early() {
+    let blocks = line();
+    let centers = vec![
+      Center { coords: (1., 0.), weight: 0. },
+      Center { coords: (6., 0.), weight: 0. },
+    ];
+    let balanced = balance_with(&blocks, &centers, 2, |p| p.augmentations < 2);
+    assert!(!balanced.completed);
+    assert_eq!(balanced.progress.augmentations, 2);
+    // everything is still assigned, and the populations add up
+    assert_eq!(balanced.populations.iter().sum::<u64>(), 60);
+    assert!(balanced.progress.routed_population < 60);
+  }
+
+  #[test]
+  fn test_balance_without_centers() {
+    let balanced = balance_with(&line(), &[], 1, |_| true);
+    assert!(balanced.populations.is_empty());
+    assert_eq!(balanced.assignment.len(), 6);
+  }
+}
@@ -0,0 +1,94 @@
+// Checks on block data before anything is solved with it, and on plans made
+// of it before they're scored or compared
+//
+// The solver assumes there is at least one block and that every coordinate is
+// a real number. A NaN in there quietly poisons every center it touches.
+// Anything working over an assignment assumes one district per block, each
+// below the number of districts.
+
+use std::error::Error;
+use std::fmt;
+use crate::solver::BlockEntry;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockError {
+  // no blocks at all
+  Empty,
+  // the block at this index has a NaN or infinite coordinate
+  NonFinite { index: usize },
+}
+
+impl fmt::Display for BlockError {
+  fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      BlockError::Empty => write!(f, "There are no blocks"),
+      BlockError::NonFinite { index } => write!(f, "Block {} has a coordinate that isn't a finite number", index),
+    }
+  }
+}
+
+impl Error for BlockError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AssignmentError {
+  // not one district for every block
+  WrongLength { blocks: usize, assigned: usize },
+  // the block at this index is in a district that doesn't exist
+  OutOfRange { index: usize, district: u32, num_districts: usize },
+}
+
+impl fmt::Display for AssignmentError {
+  fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      AssignmentError::WrongLength { blocks, assigned } => {
+        write!(f, "The plan assigns {} blocks but there are {}", assigned, blocks)
+      },
+      AssignmentError::OutOfRange { index, district, num_districts } => {
+        write!(f, "Block {} is in district {} of a plan with {} districts", index, district, num_districts)
+      },
+    }
+  }
+}
+
+impl Error for AssignmentError {}
+
+pub fn check_assignment(num_blocks : usize, assignment : &[u32], num_districts : usize) -> Result<(), AssignmentError> {
+  if assignment.len() != num_blocks {
+    return Err(AssignmentError::WrongLength { blocks: num_blocks, assigned: assignment.len() });
+  }
+  match assignment.iter().position(|&d| d as usize >= num_districts) {
+    Some(index) => Err(AssignmentError::OutOfRange { index, district: assignment[index], num_districts }),
+    None => Ok(()),
+  }
+}
+
+pub fn check_blocks(blocks : &[BlockEntry]) -> Result<(), BlockError> {
+  if blocks.is_empty() {
+    return Err(BlockError::Empty);
+  }
+  match blocks.iter().position(|b| !b.coords.0.is_finite() || !b.coords.1.is_finite()) {
+    Some(index) => Err(BlockError::NonFinite { index }),
+    None => Ok(()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_check_blocks() {
+    let block = |x, y| BlockEntry { coords: (x, y), population: 1 };
+    assert_eq!(check_blocks(&[]), Err(BlockError::Empty));
+    assert_eq!(check_blocks(&[block(0., 1.), block(f64::NAN, 1.)]), Err(BlockError::NonFinite { index: 1 }));
+    assert_eq!(check_blocks(&[block(0., f64::INFINITY)]), Err(BlockError::NonFinite { index: 0 }));
+    assert_eq!(check_blocks(&[block(0., 1.)]), Ok(()));
+  }
+
+  #[test]
+  fn test_check_assignment() {
+    assert_eq!(check_assignment(3, &[0, 1, 1], 2), Ok(()));
+    assert_eq!(check_assignment(3, &[0, 1], 2), Err(AssignmentError::WrongLength { blocks: 3, assigned: 2 }));
+    assert_eq!(check_assignment(3, &[0, 2, 1], 2), Err(AssignmentError::OutOfRange { index: 1, district: 2, num_districts: 2 }));
+  }
+}
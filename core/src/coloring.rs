@@ -0,0 +1,169 @@
+// Colors for districts so that neighbouring districts never share one
+//
+// The district graph comes from block adjacency when we have it (pairs of
+// blocks sharing a boundary, from the topology data-prep writes), or from the
+// power diagram of the centers when we don't: two districts are neighbours if
+// their cells (clipped to the blocks' hull, see power) share an edge.
+//
+// The graph is colored with DSATUR: color the district with the most
+// differently colored neighbours next, with the lowest color none of them
+// have. It uses few colors on map-like graphs (usually 4 or 5), and a
+// district keeps its previous color when it can, so colors don't flicker
+// from one iteration to the next.
+
+use std::cmp::Reverse;
+use std::collections::BTreeSet;
+use crate::power::power_cells;
+use crate::solver::Center;
+
+// The neighbours of every district, given pairs of districts that touch.
+// Pairs within a district and out of range districts are ignored.
+pub fn adjacency_from_pairs<I : IntoIterator<Item = (u32, u32)>>(num_districts : usize, pairs : I) -> Vec<Vec<usize>> {
+  let mut neighbours : Vec<BTreeSet<usize>> = vec![BTreeSet::new(); num_districts];
+  for (a, b) in pairs {
+    let (a, b) = (a as usize, b as usize);
+    if a != b && a < num_districts && b < num_districts {
+      neighbours[a].insert(b);
+      neighbours[b].insert(a);
+    }
+  }
+  neighbours.into_iter().map(|n| n.into_iter().collect()).collect()
+}
+
+// The neighbours of every district in the power diagram of the centers,
+// clipped to clip. Centers whose cells are empty have none.
+pub fn adjacency_from_cells(centers : &[Center], clip : &[(f64, f64)]) -> Vec<Vec<usize>> {
+  let cells = power_cells(centers, clip);
+
+  // how close to the boundary line counts as on it, relative to the clip's size
+  let (min, max) = clip.iter().fold(
+    ((f64::INFINITY, f64::INFINITY), (f64::NEG_INFINITY, f64::NEG_INFINITY)),
+    |(min, max), p| ((min.0.min(p.0), min.1.min(p.1)), (max.0.max(p.0), max.1.max(p.1)))
+  );
+  let tolerance = 1e-9 * ((max.0 - min.0).hypot(max.1 - min.1)).max(1.);
+
+  let mut pairs = vec![];
+  for (i, ci) in centers.iter().enumerate() {
+    for (j, cj) in centers.iter().enumerate().skip(i + 1) {
+      if cells[i].is_empty() || cells[j].is_empty() {
+        continue;
+      }
+      // the boundary between the two, as in power_cells
+      let (xi, yi) = ci.coords;
+      let (xj, yj) = cj.coords;
+      let normal = (2. * (xj - xi), 2. * (yj - yi));
+      let c = (xj * xj + yj * yj) - (xi * xi + yi * yi) - cj.weight + ci.weight;
+      let length = normal.0.hypot(normal.1);
+      if length == 0. {
+        continue;
+      }
+      let on_line = |p : (f64, f64)| ((normal.0 * p.0 + normal.1 * p.1 - c) / length).abs() <= tolerance;
+
+      // neighbours if an edge of one cell (of some length) lies on it
+      let cell = &cells[i];
+      let shared = (0..cell.len()).any(|e| {
+        let p = cell[e];
+        let q = cell[(e + 1) % cell.len()];
+        on_line(p) && on_line(q) && (q.0 - p.0).hypot(q.1 - p.1) > tolerance
+      });
+      if shared {
+        pairs.push((i as u32, j as u32));
+      }
+    }
+  }
+  adjacency_from_pairs(centers.len(), pairs)
+}
+
+// A color (0, 1, 2, ...) for every district, different from all of its
+// neighbours'. previous is the last coloring, if any.
+pub fn dsatur(adjacency : &[Vec<usize>], previous : &[usize]) -> Vec<usize> {
+  let n = adjacency.len();
+  let mut colors : Vec<Option<usize>> = vec![None; n];
+  // the colors of each district's colored neighbours
+  let mut saturation : Vec<BTreeSet<usize>> = vec![BTreeSet::new(); n];
+
+  for _ in 0..n {
+    let v = (0..n)
+      .filter(|&v| colors[v].is_none())
+      .max_by_key(|&v| (saturation[v].len(), adjacency[v].len(), Reverse(v)))
+      .unwrap();
+    let color = match previous.get(v) {
+      Some(&c) if !saturation[v].contains(&c) => c,
+      _ => (0..).find(|c| !saturation[v].contains(c)).unwrap(),
+    };
+    colors[v] = Some(color);
+    for &u in &adjacency[v] {
+      if u < n {
+        saturation[u].insert(color);
+      }
+    }
+  }
+
+  colors.into_iter().map(|c| c.unwrap()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn is_proper(adjacency : &[Vec<usize>], colors : &[usize]) -> bool {
+    adjacency.iter().enumerate().all(|(v, n)| n.iter().all(|&u| colors[u] != colors[v]))
+  }
+
+  #[test]
+  fn test_adjacency_from_pairs() {
+    let adjacency = adjacency_from_pairs(3, vec![(0, 1), (1, 0), (1, 1), (2, 7)]);
+    assert_eq!(adjacency, vec![vec![1], vec![0], vec![]]);
+  }
+
+  #[test]
+  fn test_adjacency_from_cells() {
+    // three districts in a row: the outer two don't touch
+    let clip = vec![(0., 0.), (30., 0.), (30., 10.), (0., 10.)];
+    let centers = vec![
+      Center { coords: (5., 5.), weight: 0. },
+      Center { coords: (15., 5.), weight: 0. },
+      Center { coords: (25., 5.), weight: 0. },
+    ];
+    assert_eq!(adjacency_from_cells(&centers, &clip), vec![vec![1], vec![0, 2], vec![1]]);
+
+    // four around a point: only the diagonals don't touch, wherever the blocks are
+    let clip = vec![(0., 0.), (10., 0.), (10., 10.), (0., 10.)];
+    let centers = vec![
+      Center { coords: (2., 2.), weight: 0. },
+      Center { coords: (8., 2.), weight: 0. },
+      Center { coords: (8., 8.), weight: 0. },
+      Center { coords: (2., 8.), weight: 0. },
+    ];
+    assert_eq!(adjacency_from_cells(&centers, &clip), vec![vec![1, 3], vec![0, 2], vec![1, 3], vec![0, 2]]);
+
+    // an outweighed center has no cell, so no neighbours
+    let centers = vec![
+      Center { coords: (2., 5.), weight: 0. },
+      Center { coords: (5., 5.), weight: 0. },
+      Center { coords: (8., 5.), weight: 40. },
+    ];
+    let adjacency = adjacency_from_cells(&centers, &clip);
+    assert!(adjacency[1].is_empty());
+    assert_eq!(adjacency[0], vec![2]);
+  }
+
+  #[test]
+  fn test_dsatur() {
+    // a wheel: a hub touching a ring of five, which needs four colors
+    let mut adjacency = vec![vec![1, 2, 3, 4, 5]];
+    for i in 0..5 {
+      adjacency.push(vec![0, 1 + (i + 1) % 5, 1 + (i + 4) % 5]);
+    }
+    let colors = dsatur(&adjacency, &[]);
+    assert!(is_proper(&adjacency, &colors));
+    assert_eq!(colors.iter().max(), Some(&3));
+
+    // previous colors are kept where they still work
+    let path = vec![vec![1], vec![0, 2], vec![1]];
+    assert_eq!(dsatur(&path, &[2, 0, 2]), vec![2, 0, 2]);
+    // and changed where they don't
+    let colors = dsatur(&path, &[1, 1, 1]);
+    assert!(is_proper(&path, &colors));
+  }
+}
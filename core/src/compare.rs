@@ -0,0 +1,204 @@
+// How alike two plans of the same blocks are. Everything is weighted by
+// population, so it's about how many people end up in a different district
+// rather than how many blocks.
+//
+// * overlap: population in district i of the first plan and j of the second
+// * pairing: the districts of the second plan matched one to one with those
+//   of the first so the most people stay together (Hungarian algorithm)
+// * changed_fraction: the share of people who aren't in their paired district
+// * variation_of_information: H(A) + H(B) - 2 I(A; B) in nats. 0 when identical.
+// * rand_index: the share of pairs of people the plans agree on (together in
+//   both or apart in both). 1 when identical.
+
+use serde::{Serialize, Deserialize};
+use crate::blocks::{check_assignment, AssignmentError};
+use crate::solver::BlockEntry;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Comparison {
+  pub overlap: Vec<Vec<u64>>,
+  // the district of the second plan paired with each of the first, if any
+  pub pairing: Vec<Option<usize>>,
+  pub changed_fraction: f64,
+  pub variation_of_information: f64,
+  pub rand_index: f64,
+}
+
+// Minimum cost assignment of rows to columns of a square matrix, in O(n^3).
+// Returns the column for each row.
+// https://en.wikipedia.org/wiki/Hungarian_algorithm
+fn hungarian(cost : &[Vec<f64>]) -> Vec<usize> {
+  let n = cost.len();
+  // potentials and matching are 1 based, with 0 as a free "virtual" column
+  let mut u = vec![0.; n + 1];
+  let mut v = vec![0.; n + 1];
+  let mut row_of = vec![0; n + 1];
+  let mut way = vec![0; n + 1];
+
+  for row in 1..=n {
+    row_of[0] = row;
+    let mut col = 0;
+    let mut min_to = vec![f64::INFINITY; n + 1];
+    let mut used = vec![false; n + 1];
+    loop {
+      used[col] = true;
+      let r = row_of[col];
+      let mut delta = f64::INFINITY;
+      let mut next = 0;
+      for j in 1..=n {
+        if used[j] {
+          continue;
+        }
+        let reduced = cost[r - 1][j - 1] - u[r] - v[j];
+        if reduced < min_to[j] {
+          min_to[j] = reduced;
+          way[j] = col;
+        }
+        if min_to[j] < delta {
+          delta = min_to[j];
+          next = j;
+        }
+      }
+      for j in 0..=n {
+        if used[j] {
+          u[row_of[j]] += delta;
+          v[j] -= delta;
+        } else {
+          min_to[j] -= delta;
+        }
+      }
+      col = next;
+      if row_of[col] == 0 {
+        break;
+      }
+    }
+    // flip the augmenting path
+    while col != 0 {
+      let prev = way[col];
+      row_of[col] = row_of[prev];
+      col = prev;
+    }
+  }
+
+  let mut col_of = vec![0; n];
+  for j in 1..=n {
+    if row_of[j] > 0 {
+      col_of[row_of[j] - 1] = j - 1;
+    }
+  }
+  col_of
+}
+
+fn entropy(counts : &[u64], total : f64) -> f64 {
+  counts.iter().filter(|&&c| c > 0).map(|&c| {
+    let p = c as f64 / total;
+    -p * p.ln()
+  }).sum()
+}
+
+fn pairs(n : u64) -> f64 {
+  let n = n as f64;
+  n * (n - 1.) / 2.
+}
+
+// Compare two assignments of the blocks, with num_a and num_b districts
+pub fn compare(blocks : &[BlockEntry], a : &[u32], num_a : usize, b : &[u32], num_b : usize) -> Result<Comparison, AssignmentError> {
+  check_assignment(blocks.len(), a, num_a)?;
+  check_assignment(blocks.len(), b, num_b)?;
+
+  let mut overlap = vec![vec![0u64; num_b]; num_a];
+  for ((block, &i), &j) in blocks.iter().zip(a).zip(b) {
+    overlap[i as usize][j as usize] += block.population as u64;
+  }
+
+  let row_sums : Vec<u64> = overlap.iter().map(|row| row.iter().sum()).collect();
+  let col_sums : Vec<u64> = (0..num_b).map(|j| overlap.iter().map(|row| row[j]).sum()).collect();
+  let total : u64 = row_sums.iter().sum();
+  let n = total.max(1) as f64;
+
+  // most overlap is least cost. Pad to square with districts that overlap nothing.
+  let size = num_a.max(num_b);
+  let cost : Vec<Vec<f64>> = (0..size).map(|i| (0..size).map(|j| {
+    let shared = overlap.get(i).and_then(|row| row.get(j)).cloned().unwrap_or(0);
+    -(shared as f64)
+  }).collect()).collect();
+  let pairing : Vec<Option<usize>> = hungarian(&cost).into_iter().take(num_a)
+    .map(|j| if j < num_b { Some(j) } else { None })
+    .collect();
+  let kept : u64 = pairing.iter().enumerate()
+    .filter_map(|(i, j)| j.map(|j| overlap[i][j]))
+    .sum();
+
+  let cells : Vec<u64> = overlap.iter().flatten().cloned().collect();
+  let mutual_information : f64 = overlap.iter().enumerate().flat_map(|(i, row)| {
+    let (row_sums, col_sums) = (&row_sums, &col_sums);
+    row.iter().enumerate().filter(|(_, &c)| c > 0).map(move |(j, &c)| {
+      let p = c as f64 / n;
+      p * (c as f64 * n / (row_sums[i] as f64 * col_sums[j] as f64)).ln()
+    })
+  }).sum();
+  let variation_of_information = (entropy(&row_sums, n) + entropy(&col_sums, n) - 2. * mutual_information).max(0.);
+
+  let all_pairs = pairs(total);
+  let together_in_a : f64 = row_sums.iter().map(|&c| pairs(c)).sum();
+  let together_in_b : f64 = col_sums.iter().map(|&c| pairs(c)).sum();
+  let together_in_both : f64 = cells.iter().map(|&c| pairs(c)).sum();
+  let rand_index = if all_pairs > 0. {
+    (all_pairs - together_in_a - together_in_b + 2. * together_in_both) / all_pairs
+  } else {
+    1.
+  };
+
+  Ok(Comparison {
+    overlap,
+    pairing,
+    changed_fraction: if total > 0 { 1. - kept as f64 / total as f64 } else { 0. },
+    variation_of_information,
+    rand_index,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn blocks(n : usize) -> Vec<BlockEntry> {
+    (0..n).map(|i| BlockEntry { coords: (i as f64, 0.), population: 10 }).collect()
+  }
+
+  #[test]
+  fn test_hungarian() {
+    let cost = vec![vec![4., 1., 3.], vec![2., 0., 5.], vec![3., 2., 2.]];
+    assert_eq!(hungarian(&cost), vec![1, 0, 2]);
+  }
+
+  #[test]
+  fn test_relabelled_plans_are_identical() {
+    let blocks = blocks(6);
+    let comparison = compare(&blocks, &[0, 0, 1, 1, 2, 2], 3, &[2, 2, 0, 0, 1, 1], 3).unwrap();
+    assert_eq!(comparison.pairing, vec![Some(2), Some(0), Some(1)]);
+    assert_eq!(comparison.overlap[0], vec![0, 0, 20]);
+    assert_eq!(comparison.changed_fraction, 0.);
+    assert!(comparison.variation_of_information.abs() < 1e-12);
+    assert_eq!(comparison.rand_index, 1.);
+  }
+
+  #[test]
+  fn test_different_plans() {
+    let blocks = blocks(4);
+    // one block of four moves, and the second plan has an extra district
+    let comparison = compare(&blocks, &[0, 0, 1, 1], 2, &[0, 1, 1, 2], 3).unwrap();
+    assert_eq!(comparison.overlap, vec![vec![10, 10, 0], vec![0, 10, 10]]);
+    assert_eq!(comparison.pairing.iter().filter(|p| p.is_some()).count(), 2);
+    assert_eq!(comparison.changed_fraction, 0.5);
+    assert!(comparison.variation_of_information > 0.);
+    assert!(comparison.rand_index < 1.);
+
+    assert!(compare(&blocks, &[0, 0, 1], 2, &[0, 1, 1, 2], 3).is_err());
+    // a district past the end is an error, not a panic
+    assert_eq!(
+      compare(&blocks, &[0, 0, 1, 1], 2, &[0, 1, 1, 3], 3).unwrap_err(),
+      AssignmentError::OutOfRange { index: 3, district: 3, num_districts: 3 }
+    );
+  }
+}
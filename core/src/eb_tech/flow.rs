@@ -128,10 +128,23 @@ impl FlowGraph {
     ///
     /// Panics if the flow or cost overflow a 64-bit signed integer.
     pub fn mcf(&self, s: usize, t: usize) -> (i64, i64, Vec<i64>) {
+        self.mcf_with(s, t, |_, _, _| true)
+    }
+
+    /// Like mcf, but calls on_augment(augmentations, cost, flow) after every
+    /// augmenting path. Returning false stops early, leaving a feasible flow
+    /// that is smaller than the maximum.
+    pub fn mcf_with<F>(&self, s: usize, t: usize, mut on_augment: F) -> (i64, i64, Vec<i64>)
+    where
+        F: FnMut(usize, i64, i64) -> bool,
+    {
         let mut pot = vec![0; self.graph.num_v()];
 
         // Bellman-Ford deals with negative-cost edges at initialization.
-        for _ in 1..self.graph.num_v() {
+        // It's O(VE), so skip it when there aren't any.
+        let negative = (0..self.graph.num_e()).any(|e| self.cap[e] > 0 && self.cost[e] < 0);
+        let rounds = if negative { self.graph.num_v() } else { 1 };
+        for _ in 1..rounds {
             for e in 0..self.graph.num_e() {
                 if self.cap[e] > 0 {
                     let u = self.graph.endp[e ^ 1];
@@ -143,20 +156,27 @@ impl FlowGraph {
 
         let mut flow = vec![0; self.graph.num_e()];
         let (mut min_cost, mut max_flow) = (0, 0);
+        let mut augmentations = 0;
         loop {
             let par = self.mcf_search(s, &flow, &mut pot);
-            if par[t] == None {
+            if par[t].is_none() {
                 break;
             }
             let (dc, df) = self.mcf_augment(t, &par, &mut flow);
             min_cost += dc;
             max_flow += df;
+            augmentations += 1;
+            if !on_augment(augmentations, min_cost, max_flow) {
+                break;
+            }
         }
         (min_cost, max_flow, flow)
     }
 
     // Maintains Johnson's potentials to prevent negative-cost residual edges.
-    // This allows running Dijkstra instead of the slower Bellman-Ford.
+    // This allows running Dijkstra instead of the slower Bellman-Ford. It finds
+    // the next vertex by a linear scan rather than a heap, so each search is
+    // O(V^2) however sparse the graph.
     fn mcf_search(&self, s: usize, flow: &[i64], pot: &mut [i64]) -> Vec<Option<usize>> {
         let mut vis = vec![false; self.graph.num_v()];
         let mut dist = vec![Self::INF; self.graph.num_v()];
@@ -226,6 +246,7 @@ mod test {
     }
 
     #[test]
+    #[allow(clippy::identity_op)]
     fn test_max_matching() {
         let mut graph = FlowGraph::new(14, 4);
 
@@ -97,7 +97,7 @@ impl Graph {
     }
 
     /// Gets vertex u's adjacency list.
-    pub fn adj_list(&self, u: usize) -> AdjListIterator {
+    pub fn adj_list(&self, u: usize) -> AdjListIterator<'_> {
         AdjListIterator {
             graph: self,
             next_e: self.first[u],
@@ -0,0 +1,203 @@
+// Plane geometry for block and district outlines, and the equal area
+// projection the block data is in.
+
+pub type Ring = Vec<(f64, f64)>;
+
+// GRS80, which proj uses for +proj=cea unless told otherwise
+const SEMI_MAJOR_AXIS : f64 = 6_378_137.;
+const FLATTENING : f64 = 1. / 298.257_222_101;
+
+fn authalic_q(sin_phi : f64, e : f64) -> f64 {
+  let e2 = e * e;
+  let con = e * sin_phi;
+  (1. - e2) * (sin_phi / (1. - con * con) - (0.5 / e) * ((1. - con) / (1. + con)).ln())
+}
+
+// Undo the equal area projection the block data is in, giving (lon, lat) in degrees
+pub fn unproject(x : f64, y : f64) -> (f64, f64) {
+  let e2 = FLATTENING * (2. - FLATTENING);
+  let e = e2.sqrt();
+  let qp = authalic_q(1., e);
+  let beta = (2. * y / (SEMI_MAJOR_AXIS * qp)).clamp(-1., 1.).asin();
+
+  // series for latitude from authalic latitude (Snyder 3-18)
+  let e4 = e2 * e2;
+  let e6 = e4 * e2;
+  let phi = beta
+    + (e2 / 3. + 31. * e4 / 180. + 517. * e6 / 5040.) * (2. * beta).sin()
+    + (23. * e4 / 360. + 251. * e6 / 3780.) * (4. * beta).sin()
+    + (761. * e6 / 45360.) * (6. * beta).sin();
+
+  ((x / SEMI_MAJOR_AXIS).to_degrees(), phi.to_degrees())
+}
+
+// The other way, from (lon, lat) in degrees to the block data's coordinates
+pub fn project(lon : f64, lat : f64) -> (f64, f64) {
+  let e = (FLATTENING * (2. - FLATTENING)).sqrt();
+  let q = authalic_q(lat.to_radians().sin(), e);
+  (SEMI_MAJOR_AXIS * lon.to_radians(), 0.5 * SEMI_MAJOR_AXIS * q)
+}
+
+// twice the signed area. Positive when counter clockwise.
+pub fn signed_area(ring : &[(f64, f64)]) -> f64 {
+  let n = ring.len();
+  (0..n).map(|i| {
+    let (x0, y0) = ring[i];
+    let (x1, y1) = ring[(i + 1) % n];
+    x0 * y1 - x1 * y0
+  }).sum()
+}
+
+pub fn contains(ring : &[(f64, f64)], p : (f64, f64)) -> bool {
+  let n = ring.len();
+  let mut inside = false;
+  let mut j = n.wrapping_sub(1);
+  for i in 0..n {
+    let (xi, yi) = ring[i];
+    let (xj, yj) = ring[j];
+    if (yi > p.1) != (yj > p.1) && p.0 < (xj - xi) * (p.1 - yi) / (yj - yi) + xi {
+      inside = !inside;
+    }
+    j = i;
+  }
+  inside
+}
+
+// Group rings into polygons of one outer ring followed by its holes, by index
+// into rings. A ring inside an odd number of others is a hole in the smallest
+// ring containing it.
+pub fn polygon_indices(rings : &[Ring]) -> Vec<Vec<usize>> {
+  let areas : Vec<f64> = rings.iter().map(|r| signed_area(r).abs()).collect();
+  let parents : Vec<Vec<usize>> = rings.iter().enumerate().map(|(i, ring)| {
+    (0..rings.len())
+      .filter(|&j| j != i && !ring.is_empty() && areas[j] > areas[i] && contains(&rings[j], ring[0]))
+      .collect()
+  }).collect();
+
+  let mut outers : Vec<usize> = vec![];
+  let mut holes : Vec<(usize, usize)> = vec![];
+  for (i, p) in parents.iter().enumerate() {
+    if p.len() % 2 == 0 {
+      outers.push(i);
+    } else {
+      let parent = *p.iter().min_by(|&&a, &&b| areas[a].partial_cmp(&areas[b]).unwrap()).unwrap();
+      holes.push((i, parent));
+    }
+  }
+
+  outers.iter().map(|&o| {
+    let mut polygon = vec![o];
+    polygon.extend(holes.iter().filter(|(_, p)| *p == o).map(|&(h, _)| h));
+    polygon
+  }).collect()
+}
+
+// Like polygon_indices, but the rings themselves. Outer rings come out
+// counter clockwise and holes clockwise.
+pub fn polygons(rings : Vec<Ring>) -> Vec<Vec<Ring>> {
+  let oriented = |i : usize, ccw : bool| {
+    let mut ring = rings[i].clone();
+    if (signed_area(&ring) > 0.) != ccw {
+      ring.reverse();
+    }
+    ring
+  };
+
+  polygon_indices(&rings).into_iter().map(|polygon| {
+    polygon.iter().enumerate().map(|(k, &i)| oriented(i, k == 0)).collect()
+  }).collect()
+}
+
+// The convex hull, counter clockwise (Andrew's monotone chain)
+pub fn convex_hull(points : &[(f64, f64)]) -> Ring {
+  let mut points = points.to_vec();
+  points.sort_by(|a, b| a.partial_cmp(b).unwrap());
+  points.dedup();
+  if points.len() < 3 {
+    return points;
+  }
+
+  let cross = |o : (f64, f64), a : (f64, f64), b : (f64, f64)| (a.0 - o.0) * (b.1 - o.1) - (a.1 - o.1) * (b.0 - o.0);
+  let mut hull : Ring = vec![];
+  // lower half left to right
+  for &p in &points {
+    while hull.len() >= 2 && cross(hull[hull.len() - 2], hull[hull.len() - 1], p) <= 0. {
+      hull.pop();
+    }
+    hull.push(p);
+  }
+  // then the upper half back again
+  let lower = hull.len() + 1;
+  for &p in points.iter().rev().skip(1) {
+    while hull.len() >= lower && cross(hull[hull.len() - 2], hull[hull.len() - 1], p) <= 0. {
+      hull.pop();
+    }
+    hull.push(p);
+  }
+  // which ends where it started
+  hull.pop();
+  hull
+}
+
+// The part of a convex ring where a.0 * x + a.1 * y <= c (Sutherland-Hodgman)
+pub fn clip_half_plane(ring : &[(f64, f64)], a : (f64, f64), c : f64) -> Ring {
+  let side = |p : (f64, f64)| a.0 * p.0 + a.1 * p.1 - c;
+  let n = ring.len();
+  let mut out = Vec::with_capacity(n + 1);
+  for i in 0..n {
+    let p = ring[i];
+    let q = ring[(i + 1) % n];
+    let (sp, sq) = (side(p), side(q));
+    if sp <= 0. {
+      out.push(p);
+    }
+    if (sp < 0. && sq > 0.) || (sp > 0. && sq < 0.) {
+      let t = sp / (sp - sq);
+      out.push((p.0 + t * (q.0 - p.0), p.1 + t * (q.1 - p.1)));
+    }
+  }
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_polygons_find_holes() {
+    let outer = vec![(0., 0.), (0., 10.), (10., 10.), (10., 0.)];
+    let hole = vec![(2., 2.), (4., 2.), (4., 4.), (2., 4.)];
+    let island = vec![(20., 0.), (21., 0.), (21., 1.), (20., 1.)];
+    let polygons = polygons(vec![hole, outer, island]);
+
+    assert_eq!(polygons.len(), 2);
+    assert_eq!(polygons[0].len(), 2);
+    assert!(signed_area(&polygons[0][0]) > 0.);
+    assert!(signed_area(&polygons[0][1]) < 0.);
+    assert_eq!(polygons[1].len(), 1);
+  }
+
+  #[test]
+  fn test_convex_hull() {
+    let points = vec![(0., 0.), (2., 0.), (1., 1.), (2., 2.), (0., 2.), (1., 0.), (0., 0.)];
+    assert_eq!(convex_hull(&points), vec![(0., 0.), (2., 0.), (2., 2.), (0., 2.)]);
+  }
+
+  #[test]
+  fn test_clip_half_plane() {
+    let square = vec![(0., 0.), (2., 0.), (2., 2.), (0., 2.)];
+    // x <= 1
+    let left = clip_half_plane(&square, (1., 0.), 1.);
+    assert!((signed_area(&left) - 4.).abs() < 1e-9);
+    assert!(clip_half_plane(&square, (1., 0.), -1.).is_empty());
+  }
+
+  #[test]
+  fn test_unproject() {
+    let (lon, lat) : (f64, f64) = (-79.5, 35.25);
+    let (x, y) = project(lon, lat);
+    let (lon2, lat2) = unproject(x, y);
+    assert!((lon - lon2).abs() < 1e-9);
+    assert!((lat - lat2).abs() < 1e-6, "{} != {}", lat, lat2);
+  }
+}
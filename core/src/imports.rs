@@ -0,0 +1,307 @@
+// Plans drawn somewhere else, like the enacted map, so they can be scored
+// next to ours. Either of:
+//
+// * a block assignment file, `GEOID,district` per line (or `|` separated, as
+//   the Census publishes them), with or without a header
+// * a GeoJSON FeatureCollection of district (Multi)Polygons in lon/lat. Each
+//   block goes to the district its centroid falls in. The district name is
+//   taken from a `district` property, or the feature's position otherwise.
+//
+// District names are sorted (numerically when they're numbers) and numbered
+// from 0 in that order.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+use crate::geometry::{self, Ring};
+use crate::solver::BlockEntry;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportFormat {
+  Baf,
+  GeoJson,
+}
+
+impl ImportFormat {
+  pub fn from_name(name : &str) -> Option<Self> {
+    match name {
+      "baf" | "csv" => Some(ImportFormat::Baf),
+      "geojson" => Some(ImportFormat::GeoJson),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Import {
+  // district index of every block, in block data order
+  pub assignment: Vec<u32>,
+  // the name of each district in the file
+  pub districts: Vec<String>,
+  // blocks outside every district, given to the nearest one (GeoJSON only)
+  pub unmatched: usize,
+}
+
+fn invalid(message : String) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn compare_names(a : &String, b : &String) -> Ordering {
+  match (a.parse::<u64>(), b.parse::<u64>()) {
+    (Ok(a), Ok(b)) => a.cmp(&b),
+    _ => a.cmp(b),
+  }
+}
+
+// number the distinct names, and give every block the number of its name
+fn number_districts(names : Vec<&str>) -> (Vec<u32>, Vec<String>) {
+  let mut districts : Vec<String> = names.iter().cloned().collect::<BTreeSet<_>>()
+    .into_iter().map(String::from).collect();
+  districts.sort_by(compare_names);
+  let index : HashMap<&str, u32> = districts.iter().enumerate().map(|(i, d)| (d.as_str(), i as u32)).collect();
+  let assignment = names.iter().map(|name| index[name]).collect();
+  (assignment, districts)
+}
+
+fn unquote(field : &str) -> &str {
+  field.trim().trim_matches('"').trim()
+}
+
+// (GEOID, district) for each line
+pub fn parse_baf(text : &str) -> io::Result<Vec<(&str, &str)>> {
+  let mut rows = vec![];
+  let mut first = true;
+  for (n, line) in text.lines().enumerate() {
+    let line = line.trim_start_matches('\u{feff}').trim();
+    if line.is_empty() {
+      continue;
+    }
+    let separator = if line.contains('|') { '|' } else { ',' };
+    let mut fields = line.split(separator).map(unquote);
+    let (geoid, district) = match (fields.next(), fields.next()) {
+      (Some(geoid), Some(district)) => (geoid, district),
+      _ => return Err(invalid(format!("Line {} should be GEOID,district", n + 1))),
+    };
+    // a header, which can come after blank lines
+    if std::mem::replace(&mut first, false) && !geoid.chars().all(|c| c.is_ascii_digit()) {
+      continue;
+    }
+    if district.is_empty() {
+      return Err(invalid(format!("Line {} has no district", n + 1)));
+    }
+    rows.push((geoid, district));
+  }
+  Ok(rows)
+}
+
+// The plan in a block assignment file. geoids are those of the blocks in
+// block data order, and every one of them has to be in the file.
+pub fn import_baf(text : &str, geoids : &[String]) -> io::Result<Import> {
+  let mut rows : HashMap<&str, &str> = HashMap::new();
+  for (geoid, district) in parse_baf(text)? {
+    if rows.insert(geoid, district).is_some() {
+      return Err(invalid(format!("Block {} is in the file more than once", geoid)));
+    }
+  }
+
+  let mut missing = geoids.iter().filter(|g| !rows.contains_key(g.as_str()));
+  if let Some(first) = missing.next() {
+    return Err(invalid(format!("{} blocks aren't in the file, {} for one", missing.count() + 1, first)));
+  }
+
+  let (assignment, districts) = number_districts(geoids.iter().map(|g| rows[g.as_str()]).collect());
+  Ok(Import { assignment, districts, unmatched: 0 })
+}
+
+fn district_name(feature : &Value, index : usize) -> String {
+  let property = feature["properties"].as_object()
+    .and_then(|p| p.iter().find(|(key, _)| key.eq_ignore_ascii_case("district")))
+    .map(|(_, value)| value);
+  match property {
+    Some(Value::String(name)) => name.trim().to_string(),
+    Some(Value::Number(n)) => n.to_string(),
+    _ => (index + 1).to_string(),
+  }
+}
+
+fn parse_ring(value : &Value) -> Option<Ring> {
+  value.as_array()?.iter().map(|p| {
+    let p = p.as_array()?;
+    Some(geometry::project(p.first()?.as_f64()?, p.get(1)?.as_f64()?))
+  }).collect()
+}
+
+// all the rings of the feature's polygons, projected like the blocks
+fn feature_rings(feature : &Value) -> Option<Vec<Ring>> {
+  let geometry = &feature["geometry"];
+  let polygons : Vec<&Value> = match geometry["type"].as_str()? {
+    "Polygon" => vec![&geometry["coordinates"]],
+    "MultiPolygon" => geometry["coordinates"].as_array()?.iter().collect(),
+    _ => return None,
+  };
+  polygons.into_iter()
+    .map(|rings| rings.as_array()?.iter().map(parse_ring).collect::<Option<Vec<Ring>>>())
+    .collect::<Option<Vec<Vec<Ring>>>>()
+    .map(|p| p.into_iter().flatten().collect())
+}
+
+type Edge = ((f64, f64), (f64, f64));
+
+// District edges bucketed into horizontal bands, so testing a point only looks
+// at the edges that cross its band
+struct EdgeBands {
+  min_y: f64,
+  band_height: f64,
+  bands: Vec<Vec<(usize, Edge)>>,
+}
+
+impl EdgeBands {
+  fn new(districts : &[Vec<Ring>]) -> Self {
+    let edges : Vec<(usize, Edge)> = districts.iter().enumerate().flat_map(|(d, rings)| {
+      rings.iter().flat_map(move |ring| {
+        (0..ring.len()).map(move |i| (d, (ring[i], ring[(i + 1) % ring.len()])))
+      })
+    }).collect();
+
+    let (min_y, max_y) = edges.iter().fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), (_, (a, _))| {
+      (min.min(a.1), max.max(a.1))
+    });
+    let count = (edges.len() / 8).clamp(1, 4096);
+    let band_height = ((max_y - min_y) / count as f64).max(1e-9);
+
+    let mut index = EdgeBands { min_y, band_height, bands: vec![vec![]; count] };
+    for (d, (a, b)) in edges {
+      let (from, to) = (index.band(a.1.min(b.1)), index.band(a.1.max(b.1)));
+      for band in &mut index.bands[from..=to] {
+        band.push((d, (a, b)));
+      }
+    }
+    index
+  }
+
+  fn band(&self, y : f64) -> usize {
+    let band = ((y - self.min_y) / self.band_height).floor();
+    (band.max(0.) as usize).min(self.bands.len() - 1)
+  }
+
+  // the district containing the point, by counting edge crossings
+  fn district_at(&self, p : (f64, f64), num_districts : usize) -> Option<usize> {
+    if p.1 < self.min_y {
+      return None;
+    }
+    let mut inside = vec![false; num_districts];
+    for &(d, ((x0, y0), (x1, y1))) in &self.bands[self.band(p.1)] {
+      if (y0 > p.1) != (y1 > p.1) && p.0 < (x1 - x0) * (p.1 - y0) / (y1 - y0) + x0 {
+        inside[d] = !inside[d];
+      }
+    }
+    inside.iter().position(|&i| i)
+  }
+
+  // the district with the closest corner, looking in ever wider bands
+  fn nearest(&self, p : (f64, f64)) -> Option<usize> {
+    let center = self.band(p.1);
+    for reach in 0..self.bands.len() {
+      let from = center.saturating_sub(reach);
+      let to = (center + reach).min(self.bands.len() - 1);
+      let closest = self.bands[from..=to].iter().flatten()
+        .map(|&(d, (a, _))| (d, (a.0 - p.0).powi(2) + (a.1 - p.1).powi(2)))
+        .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal));
+      if let Some((d, _)) = closest {
+        return Some(d);
+      }
+    }
+    None
+  }
+}
+
+// The plan in a GeoJSON FeatureCollection of districts
+pub fn import_geojson(bytes : &[u8], blocks : &[BlockEntry]) -> io::Result<Import> {
+  let collection : Value = serde_json::from_slice(bytes)?;
+  let features = collection["features"].as_array()
+    .ok_or_else(|| invalid("Expected a GeoJSON FeatureCollection".to_string()))?;
+
+  // features with the same name are parts of the same district
+  let names : Vec<String> = features.iter().enumerate().map(|(i, f)| district_name(f, i)).collect();
+  let (feature_districts, districts) = number_districts(names.iter().map(|n| n.as_str()).collect());
+  let mut rings : Vec<Vec<Ring>> = vec![vec![]; districts.len()];
+  for (i, feature) in features.iter().enumerate() {
+    let feature_rings = feature_rings(feature)
+      .ok_or_else(|| invalid(format!("Feature {} should be a Polygon or MultiPolygon", i + 1)))?;
+    rings[feature_districts[i] as usize].extend(feature_rings);
+  }
+  if rings.iter().all(|r| r.is_empty()) {
+    return Err(invalid("There are no districts in the file".to_string()));
+  }
+
+  let bands = EdgeBands::new(&rings);
+  let found : Vec<Option<usize>> = blocks.iter().map(|b| bands.district_at(b.coords, districts.len())).collect();
+  let unmatched = found.iter().filter(|d| d.is_none()).count();
+  if unmatched == blocks.len() && !blocks.is_empty() {
+    return Err(invalid("None of the blocks are inside the districts. Are they for another state?".to_string()));
+  }
+
+  // blocks just over a simplified border still belong somewhere
+  let assignment = found.iter().zip(blocks)
+    .map(|(d, b)| d.or_else(|| bands.nearest(b.coords)).unwrap_or(0) as u32)
+    .collect();
+  Ok(Import { assignment, districts, unmatched })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn geoids(list : &[&str]) -> Vec<String> {
+    list.iter().map(|g| g.to_string()).collect()
+  }
+
+  #[test]
+  fn test_import_baf() {
+    let text = "GEOID,CD\n370010001001000,10\n370010001001001,2\n\"370010001001002\",10\n370010001001003,ZZ\n";
+    let import = import_baf(text, &geoids(&["370010001001002", "370010001001001", "370010001001000"])).unwrap();
+    assert_eq!(import.districts, vec!["2", "10"]);
+    assert_eq!(import.assignment, vec![1, 0, 1]);
+
+    // census style, with no header
+    let import = import_baf("370010001001000|01\n370010001001001|02", &geoids(&["370010001001001"])).unwrap();
+    assert_eq!(import.assignment, vec![0]);
+
+    let missing = import_baf("GEOID,CD\n370010001001000,1", &geoids(&["370010001001000", "370010001001001"]));
+    assert_eq!(missing.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+    // a BOM and a blank line before the header
+    let import = import_baf("\u{feff}\n\nGEOID,CD\n370010001001000,3\n", &geoids(&["370010001001000"])).unwrap();
+    assert_eq!(import.districts, vec!["3"]);
+
+    let twice = import_baf("370010001001000,1\n370010001001000,2", &geoids(&["370010001001000"]));
+    assert_eq!(twice.unwrap_err().kind(), io::ErrorKind::InvalidData);
+  }
+
+  #[test]
+  fn test_import_geojson() {
+    // two districts side by side, the second in two parts
+    let square = |x : f64| serde_json::json!([[[x, 35.], [x + 1., 35.], [x + 1., 36.], [x, 36.], [x, 35.]]]);
+    let collection = serde_json::json!({
+      "type": "FeatureCollection",
+      "features": [
+        { "type": "Feature", "properties": { "DISTRICT": "1" }, "geometry": { "type": "Polygon", "coordinates": square(-80.) } },
+        { "type": "Feature", "properties": { "DISTRICT": "2" }, "geometry": { "type": "MultiPolygon", "coordinates": [square(-79.), square(-78.)] } },
+      ],
+    });
+    let block = |lon, lat| BlockEntry { coords: geometry::project(lon, lat), population: 10 };
+    let blocks = vec![block(-79.5, 35.5), block(-78.5, 35.5), block(-77.5, 35.2), block(-80.01, 35.5)];
+
+    let import = import_geojson(collection.to_string().as_bytes(), &blocks).unwrap();
+    assert_eq!(import.districts, vec!["1", "2"]);
+    assert_eq!(import.assignment, vec![0, 1, 1, 0]);
+    assert_eq!(import.unmatched, 1);
+
+    let elsewhere = vec![block(10., 50.)];
+    assert!(import_geojson(collection.to_string().as_bytes(), &elsewhere).is_err());
+  }
+}
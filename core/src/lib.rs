@@ -0,0 +1,20 @@
+// The districting algorithms and the statistics around them.
+//
+// Nothing in here knows about browsers or servers, so the wasm app, the
+// server and data-prep all build on it and it can be tested with plain
+// `cargo test`.
+
+pub mod solver;
+pub mod blocks;
+pub mod stats;
+pub mod geometry;
+pub mod imports;
+pub mod compare;
+pub mod balance;
+pub mod coloring;
+pub mod power;
+pub mod eb_tech;
+
+pub use solver::{Algorithm, BlockEntry, Center, PlanReport, Solver, SolverConfig};
+pub use blocks::{check_assignment, check_blocks, AssignmentError, BlockError};
+pub use stats::{RunningStatistics, RunningStatisticsResults};
@@ -0,0 +1,126 @@
+// Power diagram (weighted voronoi) cells of the centers
+//
+// A point goes to the center with the lowest |p - c|^2 - weight, which is how
+// the solver assigns blocks, so these cells are the real decision boundaries
+// of an assignment. The boundary between centers i and j is the line
+//
+//   2 p . (cj - ci) = |cj|^2 - |ci|^2 - wj + wi
+//
+// so a cell is its clip region cut by one half plane for every other center.
+// Cells are convex, and can be empty when a center is outweighed by its
+// neighbours. The clip region has to be convex too: the bounding box of the
+// blocks or their convex hull.
+
+use crate::geometry::{clip_half_plane, convex_hull, Ring};
+use crate::solver::{BlockEntry, Center};
+
+// The bounding box of the blocks, counter clockwise
+pub fn bounding_box(blocks : &[BlockEntry]) -> Ring {
+  if blocks.is_empty() {
+    return vec![];
+  }
+  let (min, max) = blocks.iter().fold(
+    ((f64::INFINITY, f64::INFINITY), (f64::NEG_INFINITY, f64::NEG_INFINITY)),
+    |(min, max), b| ((min.0.min(b.coords.0), min.1.min(b.coords.1)), (max.0.max(b.coords.0), max.1.max(b.coords.1)))
+  );
+  vec![min, (max.0, min.1), max, (min.0, max.1)]
+}
+
+// The convex hull of the blocks, counter clockwise
+pub fn hull(blocks : &[BlockEntry]) -> Ring {
+  let points : Vec<(f64, f64)> = blocks.iter().map(|b| b.coords).collect();
+  convex_hull(&points)
+}
+
+// Every center's cell within clip, counter clockwise. Empty if it has none.
+pub fn power_cells(centers : &[Center], clip : &[(f64, f64)]) -> Vec<Ring> {
+  centers.iter().enumerate().map(|(i, ci)| {
+    let (xi, yi) = ci.coords;
+    let mut cell : Ring = clip.to_vec();
+    for (j, cj) in centers.iter().enumerate() {
+      if i == j || cell.is_empty() {
+        continue;
+      }
+      let (xj, yj) = cj.coords;
+      if xi == xj && yi == yj {
+        // on top of each other: the heavier one takes it all. Ties go to the first.
+        if cj.weight > ci.weight || (cj.weight == ci.weight && j < i) {
+          cell.clear();
+        }
+        continue;
+      }
+      let normal = (2. * (xj - xi), 2. * (yj - yi));
+      let c = (xj * xj + yj * yj) - (xi * xi + yi * yi) - cj.weight + ci.weight;
+      cell = clip_half_plane(&cell, normal, c);
+    }
+    // slivers from rounding aren't cells
+    if cell.len() < 3 { vec![] } else { cell }
+  }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::geometry::{contains, signed_area};
+
+  fn square() -> Ring {
+    vec![(0., 0.), (4., 0.), (4., 4.), (0., 4.)]
+  }
+
+  #[test]
+  fn test_unweighted_is_voronoi() {
+    let centers = vec![
+      Center { coords: (1., 2.), weight: 0. },
+      Center { coords: (3., 2.), weight: 0. },
+    ];
+    let cells = power_cells(&centers, &square());
+    // split down x = 2. (signed_area is twice the area.)
+    assert!((signed_area(&cells[0]) - 16.).abs() < 1e-9);
+    assert!((signed_area(&cells[1]) - 16.).abs() < 1e-9);
+    assert!(cells[0].iter().all(|p| p.0 <= 2. + 1e-9));
+  }
+
+  #[test]
+  fn test_weights_move_the_boundary() {
+    let centers = vec![
+      Center { coords: (1., 2.), weight: 4. },
+      Center { coords: (3., 2.), weight: 0. },
+    ];
+    let cells = power_cells(&centers, &square());
+    // the boundary moves to where (x - 1)^2 - 4 = (x - 3)^2, which is x = 3
+    assert!(cells[0].iter().any(|p| (p.0 - 3.).abs() < 1e-9));
+    assert!((signed_area(&cells[0]) - 24.).abs() < 1e-9);
+
+    // and every block lands in the cell of the center it's assigned to
+    let blocks : Vec<BlockEntry> = (0..16).map(|i| BlockEntry {
+      coords: ((i % 4) as f64 + 0.4, (i / 4) as f64 + 0.3),
+      population: 1,
+    }).collect();
+    let mut assignment = vec![0; blocks.len()];
+    let mut populations = vec![0; 2];
+    crate::solver::assign_to_centers(&blocks, &centers, &mut assignment, &mut populations);
+    for (b, &d) in blocks.iter().zip(&assignment) {
+      assert!(contains(&cells[d as usize], b.coords));
+    }
+  }
+
+  #[test]
+  fn test_outweighed_center_has_no_cell() {
+    let centers = vec![
+      Center { coords: (2., 2.), weight: 0. },
+      Center { coords: (2.5, 2.), weight: 100. },
+    ];
+    let cells = power_cells(&centers, &square());
+    assert!(cells[0].is_empty());
+    assert_eq!(cells[1].len(), 4);
+  }
+
+  #[test]
+  fn test_clip_regions() {
+    let blocks : Vec<BlockEntry> = vec![(0., 0.), (2., 0.), (1., 1.), (1., 2.)].into_iter()
+      .map(|coords| BlockEntry { coords, population: 1 })
+      .collect();
+    assert_eq!(bounding_box(&blocks), vec![(0., 0.), (2., 0.), (2., 2.), (0., 2.)]);
+    assert_eq!(hull(&blocks), vec![(0., 0.), (2., 0.), (1., 2.)]);
+  }
+}
@@ -0,0 +1,546 @@
+// Population balanced districting by weighted k-means.
+//
+// Every iteration assigns blocks to centers and then moves each center to the
+// population weighted centroid of its blocks. Assignment uses a power diagram:
+// a block goes to the center minimising d^2 - weight, and the weights are
+// adjusted until every district holds close to the target population.
+
+use serde::{Serialize, Deserialize};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use crate::balance::{self, BalanceProgress};
+use crate::blocks::{check_assignment, AssignmentError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct BlockEntry {
+  pub coords: (f64, f64),
+  pub population: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Center {
+  pub coords: (f64, f64),
+  pub weight: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+  // power diagram assignment, balanced to equal population
+  #[default]
+  Power,
+  // plain voronoi assignment (unweighted k-means). Not population balanced.
+  Lloyd,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SolverConfig {
+  pub num_districts: usize,
+  #[serde(default)]
+  pub algorithm: Algorithm,
+  #[serde(default)]
+  pub seed: u64,
+  #[serde(default = "SolverConfig::default_max_iterations")]
+  pub max_iterations: usize,
+  // stop once no center moves further than this (in block coordinate units)
+  #[serde(default = "SolverConfig::default_tolerance")]
+  pub tolerance: f64,
+}
+
+impl SolverConfig {
+  // the defaults for everything but the number of districts
+  pub fn new(num_districts : usize) -> Self {
+    Self {
+      num_districts,
+      algorithm: Algorithm::default(),
+      seed: 0,
+      max_iterations: Self::default_max_iterations(),
+      tolerance: Self::default_tolerance(),
+    }
+  }
+
+  fn default_max_iterations() -> usize { 100 }
+  fn default_tolerance() -> f64 { 1. }
+}
+
+// Diagnostics for one assign + relocate iteration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StepReport {
+  pub iteration: usize,
+  pub max_movement: f64,
+  // cost() of the assignment this step made, before the centers moved
+  #[serde(default)]
+  pub total_cost: f64,
+  // largest deviation of a district from the target population, as a fraction
+  pub max_deviation: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DistrictReport {
+  pub population: u64,
+  pub deviation: f64,
+  pub center: Center,
+  pub num_blocks: usize,
+  // population weighted mean squared distance of blocks to the center.
+  // Smaller is more compact.
+  pub moment_of_inertia: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlanReport {
+  pub total_population: u64,
+  pub target_population: f64,
+  pub max_deviation: f64,
+  // 100 * (max - min) / mean of max and min
+  pub population_spread_percent: f64,
+  pub iterations: usize,
+  pub converged: bool,
+  pub districts: Vec<DistrictReport>,
+}
+
+fn distance_sq(a : (f64, f64), b : (f64, f64)) -> f64 {
+  let dx = a.0 - b.0;
+  let dy = a.1 - b.1;
+  dx * dx + dy * dy
+}
+
+// Assign every block to the center with the lowest power distance, and total
+// up the population of each. assignment is one per block, populations one per center.
+// With no centers there's nowhere to put them, so the assignment is left alone.
+pub fn assign_to_centers(blocks : &[BlockEntry], centers : &[Center], assignment : &mut [u32], populations : &mut [u64]) {
+  populations.iter_mut().for_each(|p| *p = 0);
+  if centers.is_empty() {
+    return;
+  }
+
+  for (block, assigned) in blocks.iter().zip(assignment.iter_mut()) {
+    let mut best = 0;
+    let mut best_cost = f64::INFINITY;
+    for (i, c) in centers.iter().enumerate() {
+      let cost = distance_sq(block.coords, c.coords) - c.weight;
+      if cost < best_cost {
+        best_cost = cost;
+        best = i;
+      }
+    }
+    *assigned = best as u32;
+    populations[best] += block.population as u64;
+  }
+}
+
+// How many balancing rounds to do per assignment, and how close is close enough
+const BALANCE_ROUNDS : usize = 50;
+const BALANCE_TOLERANCE : f64 = 0.005;
+
+// Generic over how the blocks are held, so it can borrow them (the server
+// shares one copy between solves) or own them (the wasm app keeps one around).
+pub struct Solver<B : AsRef<[BlockEntry]>> {
+  blocks: B,
+  config: SolverConfig,
+  centers: Vec<Center>,
+  assignment: Vec<u32>,
+  populations: Vec<u64>,
+  total_population: u64,
+  // typical squared spacing between centers, used to scale weight updates
+  spacing_sq: f64,
+  iteration: usize,
+  converged: bool,
+}
+
+impl<B : AsRef<[BlockEntry]>> Solver<B> {
+  pub fn new(blocks : B, config : SolverConfig) -> Self {
+    let all_blocks = blocks.as_ref();
+    let k = config.num_districts.max(1);
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let total_population : u64 = all_blocks.iter().map(|b| b.population as u64).sum();
+
+    // seed the centers on randomly chosen blocks, more populous blocks being more likely
+    let centers = (0..k).map(|_| {
+      let mut pick = rng.gen_range(0, total_population.max(1));
+      let block = all_blocks.iter().find(|b| {
+        if pick < b.population as u64 { return true; }
+        pick -= b.population as u64;
+        false
+      }).or_else(|| all_blocks.first());
+      Center {
+        coords: block.map(|b| b.coords).unwrap_or((0., 0.)),
+        weight: 0.,
+      }
+    }).collect();
+
+    let (min, max) = all_blocks.iter().fold(
+      ((f64::INFINITY, f64::INFINITY), (f64::NEG_INFINITY, f64::NEG_INFINITY)),
+      |(min, max), b| ((min.0.min(b.coords.0), min.1.min(b.coords.1)), (max.0.max(b.coords.0), max.1.max(b.coords.1)))
+    );
+    let area = if all_blocks.is_empty() { 1. } else { ((max.0 - min.0) * (max.1 - min.1)).max(1.) };
+    let num_blocks = all_blocks.len();
+
+    Self {
+      blocks,
+      config: SolverConfig { num_districts: k, ..config },
+      centers,
+      assignment: vec![0; num_blocks],
+      populations: vec![0; k],
+      total_population,
+      spacing_sq: area / k as f64,
+      iteration: 0,
+      converged: false,
+    }
+  }
+
+  // Pick up from centers saved part way through an earlier solve
+  pub fn resume(blocks : B, config : SolverConfig, centers : Vec<Center>, iteration : usize) -> Self {
+    let mut solver = Self::new(blocks, config);
+    if centers.len() == solver.centers.len() {
+      solver.centers = centers;
+      solver.iteration = iteration;
+    }
+    solver
+  }
+
+  pub fn blocks(&self) -> &[BlockEntry] {
+    self.blocks.as_ref()
+  }
+
+  // give the blocks back, to start a new solve with them
+  pub fn into_blocks(self) -> B {
+    self.blocks
+  }
+
+  pub fn iteration(&self) -> usize {
+    self.iteration
+  }
+
+  // whether the centers have stopped moving (by more than the tolerance)
+  pub fn is_converged(&self) -> bool {
+    self.converged
+  }
+
+  pub fn assignment(&self) -> &[u32] {
+    &self.assignment
+  }
+
+  pub fn centers(&self) -> &[Center] {
+    &self.centers
+  }
+
+  // population of each district as of the last assignment
+  pub fn populations(&self) -> &[u64] {
+    &self.populations
+  }
+
+  pub fn target_population(&self) -> f64 {
+    self.total_population as f64 / self.config.num_districts as f64
+  }
+
+  pub fn max_deviation(&self) -> f64 {
+    let target = self.target_population();
+    if target == 0. { return 0.; }
+    self.populations.iter()
+      .map(|&p| (p as f64 - target).abs() / target)
+      .fold(0., f64::max)
+  }
+
+  // assign every block to its center by power distance, as the weights stand.
+  // Cheap next to assign, which also rebalances the weights, so use this to
+  // catch the assignment up with centers that just moved.
+  pub fn assign_by_power(&mut self) {
+    assign_to_centers(self.blocks.as_ref(), &self.centers, &mut self.assignment, &mut self.populations);
+  }
+
+  // nudge the weights of under populated districts up and over populated ones down
+  fn balance(&mut self) {
+    let target = self.target_population();
+    if target == 0. { return; }
+
+    for round in 0..BALANCE_ROUNDS {
+      self.assign_by_power();
+      if self.max_deviation() < BALANCE_TOLERANCE {
+        break;
+      }
+
+      // take smaller steps as we go to avoid oscillating
+      let rate = self.spacing_sq / (1. + round as f64);
+      for (c, &p) in self.centers.iter_mut().zip(self.populations.iter()) {
+        c.weight += rate * (target - p as f64) / target;
+      }
+    }
+  }
+
+  pub fn assign(&mut self) {
+    match self.config.algorithm {
+      Algorithm::Power => self.balance(),
+      Algorithm::Lloyd => self.assign_by_power(),
+    }
+  }
+
+  // move every center to the population weighted centroid of its blocks.
+  // Returns the furthest distance any center moved.
+  pub fn relocate(&mut self) -> f64 {
+    let k = self.centers.len();
+    let mut sums = vec![(0., 0., 0.); k];
+    for (block, &i) in self.blocks.as_ref().iter().zip(self.assignment.iter()) {
+      let w = block.population as f64;
+      let s = &mut sums[i as usize];
+      s.0 += block.coords.0 * w;
+      s.1 += block.coords.1 * w;
+      s.2 += w;
+    }
+
+    let mut max_movement : f64 = 0.;
+    for (c, (x, y, w)) in self.centers.iter_mut().zip(sums) {
+      if w > 0. {
+        let next = (x / w, y / w);
+        max_movement = max_movement.max(distance_sq(c.coords, next).sqrt());
+        c.coords = next;
+      }
+    }
+    max_movement
+  }
+
+  // population weighted sum of squared distances from blocks to their centers
+  pub fn cost(&self) -> f64 {
+    self.blocks.as_ref().iter().zip(self.assignment.iter())
+      .map(|(block, &i)| block.population as f64 * distance_sq(block.coords, self.centers[i as usize].coords))
+      .sum()
+  }
+
+  pub fn step(&mut self) -> StepReport {
+    self.assign();
+    let total_cost = self.cost();
+    let max_movement = self.relocate();
+    self.iteration += 1;
+    self.converged = max_movement <= self.config.tolerance;
+
+    StepReport {
+      iteration: self.iteration,
+      max_movement,
+      total_cost,
+      max_deviation: self.max_deviation(),
+    }
+  }
+
+  // iterate until the centers settle down or we run out of iterations
+  pub fn solve(&mut self) {
+    self.solve_with(|_, _| true);
+  }
+
+  // Like solve, but calls on_step after every iteration. Returning false from
+  // on_step stops the solve early, in which case this returns false.
+  pub fn solve_with<F : FnMut(&Self, &StepReport) -> bool>(&mut self, mut on_step : F) -> bool {
+    while !self.converged && self.iteration < self.config.max_iterations {
+      let report = self.step();
+      if !on_step(self, &report) {
+        return false;
+      }
+    }
+    // leave the assignment consistent with the final centers
+    self.assign();
+    true
+  }
+
+  // Replace the assignment with one balanced by min cost flow for the current
+  // centers, to within a block (see balance). Returns false if on_progress stopped it early, in
+  // which case the assignment is only partly balanced.
+  pub fn balance_with<F : FnMut(&BalanceProgress) -> bool>(&mut self, every : usize, on_progress : F) -> bool {
+    let balanced = balance::balance_with(self.blocks.as_ref(), &self.centers, every, on_progress);
+    self.assignment = balanced.assignment;
+    self.populations = balanced.populations;
+    balanced.completed
+  }
+
+  pub fn report(&self) -> PlanReport {
+    build_report(self.blocks.as_ref(), &self.assignment, &self.centers, self.iteration, self.converged)
+  }
+}
+
+fn build_report(blocks : &[BlockEntry], assignment : &[u32], centers : &[Center], iterations : usize, converged : bool) -> PlanReport {
+  let k = centers.len();
+  let mut populations = vec![0u64; k];
+  let mut inertia = vec![0.; k];
+  let mut counts = vec![0; k];
+  for (block, &i) in blocks.iter().zip(assignment.iter()) {
+    let i = i as usize;
+    populations[i] += block.population as u64;
+    inertia[i] += block.population as f64 * distance_sq(block.coords, centers[i].coords);
+    counts[i] += 1;
+  }
+
+  let total_population : u64 = blocks.iter().map(|b| b.population as u64).sum();
+  let target = if k > 0 { total_population as f64 / k as f64 } else { 0. };
+  let deviation = |population : u64| if target > 0. { (population as f64 - target) / target } else { 0. };
+
+  let districts : Vec<DistrictReport> = (0..k).map(|i| {
+    let population = populations[i];
+    DistrictReport {
+      population,
+      deviation: deviation(population),
+      center: centers[i],
+      num_blocks: counts[i],
+      moment_of_inertia: if population > 0 { inertia[i] / population as f64 } else { 0. },
+    }
+  }).collect();
+
+  let max = populations.iter().cloned().max().unwrap_or(0) as f64;
+  let min = populations.iter().cloned().min().unwrap_or(0) as f64;
+
+  PlanReport {
+    total_population,
+    target_population: target,
+    max_deviation: populations.iter().map(|&p| deviation(p).abs()).fold(0., f64::max),
+    population_spread_percent: if max + min > 0. { 100. * (max - min) / (0.5 * (max + min)) } else { 0. },
+    iterations,
+    converged,
+    districts,
+  }
+}
+
+// Score a plan that came from somewhere else (an enacted map, say) the same
+// way as solved ones. Centers are the population weighted centroids of the
+// districts. The report is the same population balance and inertia as for a
+// solved plan; fairness metrics (partisan or demographic) need election data
+// we don't have and are out of scope.
+pub fn evaluate(blocks : &[BlockEntry], assignment : &[u32], num_districts : usize) -> Result<PlanReport, AssignmentError> {
+  check_assignment(blocks.len(), assignment, num_districts)?;
+  let mut sums = vec![(0., 0., 0.); num_districts];
+  for (block, &i) in blocks.iter().zip(assignment.iter()) {
+    let w = block.population as f64;
+    let s = &mut sums[i as usize];
+    s.0 += block.coords.0 * w;
+    s.1 += block.coords.1 * w;
+    s.2 += w;
+  }
+  let centers : Vec<Center> = sums.into_iter().map(|(x, y, w)| Center {
+    coords: if w > 0. { (x / w, y / w) } else { (0., 0.) },
+    weight: 0.,
+  }).collect();
+
+  // nothing to solve, so it's as converged as it gets
+  Ok(build_report(blocks, assignment, &centers, 0, true))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn config(num_districts : usize, seed : u64) -> SolverConfig {
+    SolverConfig {
+      num_districts,
+      algorithm: Algorithm::Power,
+      seed,
+      max_iterations: 100,
+      tolerance: 1.,
+    }
+  }
+
+  // a grid of blocks with equal population
+  fn grid(n : usize) -> Vec<BlockEntry> {
+    (0..n * n).map(|i| BlockEntry {
+      coords: ((i % n) as f64 * 10., (i / n) as f64 * 10.),
+      population: 100,
+    }).collect()
+  }
+
+  #[test]
+  fn test_power_balances_population() {
+    let blocks = grid(20);
+    let mut solver = Solver::new(&blocks, config(4, 3));
+    solver.solve();
+
+    let report = solver.report();
+    assert_eq!(report.total_population, 40_000);
+    assert_eq!(report.districts.len(), 4);
+    assert!(report.max_deviation < 0.05, "deviation {}", report.max_deviation);
+    assert_eq!(report.districts.iter().map(|d| d.num_blocks).sum::<usize>(), 400);
+  }
+
+  #[test]
+  fn test_same_seed_same_plan() {
+    let blocks = grid(10);
+    let mut a = Solver::new(&blocks, config(3, 42));
+    let mut b = Solver::new(&blocks, config(3, 42));
+    a.solve();
+    b.solve();
+    assert_eq!(a.assignment(), b.assignment());
+  }
+
+  #[test]
+  fn test_step_reports_progress() {
+    let blocks = grid(10);
+    let mut solver = Solver::new(&blocks, config(2, 0));
+    let first = solver.step();
+    assert_eq!(first.iteration, 1);
+    assert!(first.max_movement > 0.);
+    assert!(first.total_cost > 0.);
+    let second = solver.step();
+    assert!(second.total_cost < first.total_cost);
+  }
+
+  #[test]
+  fn test_assign_by_power_keeps_weights() {
+    let blocks = grid(10);
+    let mut solver = Solver::new(&blocks, config(3, 0));
+    solver.step();
+    let centers = solver.centers().to_vec();
+    solver.assign_by_power();
+    assert_eq!(solver.centers(), &centers[..]);
+
+    let mut assignment = vec![0; blocks.len()];
+    let mut populations = vec![0; 3];
+    assign_to_centers(&blocks, &centers, &mut assignment, &mut populations);
+    assert_eq!(solver.assignment(), &assignment[..]);
+    assert_eq!(solver.populations(), &populations[..]);
+  }
+
+  #[test]
+  fn test_resume_matches_uninterrupted() {
+    let blocks = grid(10);
+    let mut whole = Solver::new(&blocks, config(3, 5));
+    whole.solve();
+
+    let mut first = Solver::new(&blocks, config(3, 5));
+    first.step();
+    first.step();
+    let mut rest = Solver::resume(&blocks, config(3, 5), first.centers().to_vec(), 2);
+    rest.solve();
+
+    assert_eq!(whole.assignment(), rest.assignment());
+    assert_eq!(whole.report().iterations, rest.report().iterations);
+  }
+
+  #[test]
+  fn test_solve_with_stops_early() {
+    let blocks = grid(10);
+    let mut solver = Solver::new(&blocks, config(2, 0));
+    let mut steps = 0;
+    let finished = solver.solve_with(|_, step| {
+      steps = step.iteration;
+      step.iteration < 2
+    });
+    assert!(!finished);
+    assert_eq!(steps, 2);
+  }
+
+  #[test]
+  fn test_evaluate_matches_report() {
+    let blocks = grid(10);
+    let mut solver = Solver::new(&blocks, config(3, 1));
+    solver.solve();
+
+    let solved = solver.report();
+    let evaluated = evaluate(&blocks, solver.assignment(), 3).unwrap();
+    assert_eq!(evaluated.total_population, solved.total_population);
+    assert_eq!(evaluated.max_deviation, solved.max_deviation);
+    for (a, b) in evaluated.districts.iter().zip(solved.districts.iter()) {
+      assert_eq!(a.population, b.population);
+      assert_eq!(a.num_blocks, b.num_blocks);
+    }
+
+    let mut assignment = solver.assignment().to_vec();
+    assignment[4] = 3;
+    assert_eq!(
+      evaluate(&blocks, &assignment, 3).unwrap_err(),
+      AssignmentError::OutOfRange { index: 4, district: 3, num_districts: 3 }
+    );
+  }
+}
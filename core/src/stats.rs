@@ -1,5 +1,4 @@
 use serde::{Serialize, Deserialize};
-use std::f64::{INFINITY, NEG_INFINITY};
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct RunningStatisticsResults {
@@ -21,6 +20,12 @@ pub struct RunningStatistics {
   _min : f64,
 }
 
+impl Default for RunningStatistics {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
 impl RunningStatistics {
   pub fn new() -> Self {
     Self {
@@ -28,8 +33,8 @@ impl RunningStatistics {
       s: 0.,
       n: 0,
       total: 0.,
-      _max: NEG_INFINITY,
-      _min: INFINITY,
+      _max: f64::NEG_INFINITY,
+      _min: f64::INFINITY,
     }
   }
 
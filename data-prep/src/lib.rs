@@ -0,0 +1,504 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Deserialize};
+
+use proj::Proj;
+extern crate geo_types;
+use geo_types::Point;
+
+pub mod sources;
+pub use sources::{InputFormat, RawBlock, RawBlocks, read_blocks};
+pub mod pl94;
+pub mod topology;
+pub use topology::{Topology, TopologyBuilder};
+pub mod fips;
+// shared with the app and the server, so the meta section reads back the same
+pub use redistrict_core::stats;
+pub use redistrict_core::{RunningStatistics, RunningStatisticsResults};
+
+// the equal area projection that block coordinates are written in
+pub const PROJECTION : &str = "+proj=cea";
+
+fn invalid_data<E : ToString>(e : E) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlockEntry(pub f64, pub f64, pub u32);
+
+// Get the centroid of a _cartesian_ polygon
+pub fn get_centroid(polygon : &[Point<f64>]) -> (f64, f64) {
+  let mut x = 0.;
+  let mut y = 0.;
+  let l = polygon.len() as f64;
+
+  if l == 0. {
+    return (x, y);
+  }
+
+  for p in polygon {
+    x += p.x();
+    y += p.y();
+  }
+
+  x /= l;
+  y /= l;
+
+  (x, y)
+}
+
+// get the block entry by getting the centroid of the cartesian polygon
+pub fn get_block_entry(polygon : &[Point<f64>], population : u32) -> BlockEntry {
+  let (x, y) = get_centroid(polygon);
+  BlockEntry(x, y, population)
+}
+
+// Projects (lon, lat) degrees into the equal area projection
+pub struct Projection {
+  proj: Proj,
+}
+
+impl Projection {
+  pub fn equal_area() -> io::Result<Self> {
+    let proj = Proj::new(&format!(
+      "
+      +proj=pipeline
+      +step {}
+      ",
+      PROJECTION
+    )).ok_or_else(|| invalid_data(format!("Could not set up projection {}", PROJECTION)))?;
+
+    Ok(Self { proj })
+  }
+
+  pub fn project(&self, ring : &[Point<f64>]) -> io::Result<Vec<Point<f64>>> {
+    let mut points : Vec<Point<f64>> = ring.iter().map(|p| {
+      Point::new(p.x().to_radians(), p.y().to_radians())
+    }).collect();
+    self.proj.project_array(&mut points, false).map_err(invalid_data)?;
+    Ok(points)
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BoundingBox {
+  pub min_x: f64,
+  pub min_y: f64,
+  pub max_x: f64,
+  pub max_y: f64,
+}
+
+impl BoundingBox {
+  pub fn empty() -> Self {
+    Self {
+      min_x: f64::INFINITY,
+      min_y: f64::INFINITY,
+      max_x: f64::NEG_INFINITY,
+      max_y: f64::NEG_INFINITY,
+    }
+  }
+
+  pub fn extend(&mut self, x : f64, y : f64) {
+    self.min_x = self.min_x.min(x);
+    self.min_y = self.min_y.min(y);
+    self.max_x = self.max_x.max(x);
+    self.max_y = self.max_y.max(y);
+  }
+}
+
+// Summary of the state, written after the blocks once they are all out
+#[derive(Debug, Clone, Serialize)]
+pub struct BlockDataMeta {
+  pub state_code: String,
+  pub state_name: Option<&'static str>,
+  pub vintage: Option<u32>,
+  pub block_count: usize,
+  pub blocks_read: usize,
+  pub total_population: u64,
+  pub bounding_box: BoundingBox,
+  pub projection: &'static str,
+  pub population: RunningStatisticsResults,
+  pub complete: bool,
+}
+
+impl BlockDataMeta {
+  pub fn new(state_code : String, vintage : Option<u32>) -> Self {
+    Self {
+      state_name: fips::state_name(&state_code),
+      state_code,
+      vintage,
+      block_count: 0,
+      blocks_read: 0,
+      total_population: 0,
+      bounding_box: BoundingBox::empty(),
+      projection: PROJECTION,
+      population: RunningStatistics::new().as_results(),
+      complete: true,
+    }
+  }
+}
+
+// Streams block entries out as a json array so we never hold the whole state in memory.
+// The file looks like { "blocks": [...], "meta": {...} }
+pub struct BlockWriter<W : Write> {
+  out: W,
+  population: RunningStatistics,
+  bounding_box: BoundingBox,
+}
+
+impl<W : Write> BlockWriter<W> {
+  pub fn new(mut out : W) -> io::Result<Self> {
+    out.write_all(b"{\"blocks\":[")?;
+    Ok(Self {
+      out,
+      population: RunningStatistics::new(),
+      bounding_box: BoundingBox::empty(),
+    })
+  }
+
+  pub fn count(&self) -> usize {
+    self.population.size()
+  }
+
+  pub fn write(&mut self, entry : &BlockEntry) -> io::Result<()> {
+    if self.count() > 0 {
+      self.out.write_all(b",")?;
+    }
+    serde_json::to_writer(&mut self.out, entry)?;
+    self.population.push(entry.2 as f64);
+    self.bounding_box.extend(entry.0, entry.1);
+    Ok(())
+  }
+
+  pub fn finish(mut self, mut meta : BlockDataMeta) -> io::Result<BlockDataMeta> {
+    meta.block_count = self.count();
+    meta.total_population = self.population.sum() as u64;
+    meta.bounding_box = self.bounding_box;
+    meta.population = self.population.as_results();
+
+    self.out.write_all(b"],\"meta\":")?;
+    serde_json::to_writer(&mut self.out, &meta)?;
+    self.out.write_all(b"}")?;
+    self.out.flush()?;
+    Ok(meta)
+  }
+}
+
+// Streams GEOIDs out as a json array, in the same order as the block entries
+pub struct GeoidWriter<W : Write> {
+  out: W,
+  count: usize,
+}
+
+impl<W : Write> GeoidWriter<W> {
+  pub fn new(mut out : W) -> io::Result<Self> {
+    out.write_all(b"[")?;
+    Ok(Self { out, count: 0 })
+  }
+
+  pub fn write(&mut self, geoid : &str) -> io::Result<()> {
+    if self.count > 0 {
+      self.out.write_all(b",")?;
+    }
+    serde_json::to_writer(&mut self.out, geoid)?;
+    self.count += 1;
+    Ok(())
+  }
+
+  pub fn finish(mut self) -> io::Result<()> {
+    self.out.write_all(b"]")?;
+    self.out.flush()
+  }
+}
+
+// A file written under a temporary name and only moved into place by commit,
+// so a run that fails part way leaves the old file (or none) rather than a
+// truncated one. The temporary file is removed if it's never committed.
+pub struct PendingFile {
+  path: PathBuf,
+  temp: PathBuf,
+  committed: bool,
+}
+
+impl PendingFile {
+  pub fn create<P : AsRef<Path>>(path : P) -> io::Result<(Self, File)> {
+    let path = path.as_ref().to_path_buf();
+    let mut temp = path.clone().into_os_string();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    let file = File::create(&temp)?;
+    Ok((Self { path, temp, committed: false }, file))
+  }
+
+  pub fn commit(mut self) -> io::Result<()> {
+    fs::rename(&self.temp, &self.path)?;
+    self.committed = true;
+    Ok(())
+  }
+}
+
+impl Drop for PendingFile {
+  fn drop(&mut self) {
+    if !self.committed {
+      let _ = fs::remove_file(&self.temp);
+    }
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PrepOptions {
+  // block populations from PL 94-171 files, by GEOID. These take precedence
+  // over any population attached to the geometry.
+  pub pl_populations: Option<HashMap<String, u32>>,
+  // Douglas-Peucker tolerance in metres for the block boundary topology.
+  // No topology is built when this is None. Building one holds every block
+  // outline in memory, unlike the rest of the run.
+  pub boundary_tolerance: Option<f64>,
+  pub vintage: Option<u32>,
+}
+
+pub struct PrepOutput {
+  pub meta: BlockDataMeta,
+  pub topology: Option<Topology>,
+  // the error that stopped reading before the end of the input, if any
+  pub stopped_early: Option<io::Error>,
+  // blocks missing from the PL 94-171 data, which were left with no population
+  pub unmatched_populations: usize,
+}
+
+// Work out the population of a block, preferring the PL 94-171 data when we
+// have it. None if whichever we're using doesn't have the block.
+pub fn block_population(block : &RawBlock, pl_populations : &Option<HashMap<String, u32>>) -> Option<u32> {
+  match pl_populations {
+    Some(populations) => populations.get(&block.geoid).cloned(),
+    None => block.population,
+  }
+}
+
+// The state the blocks belong to, taken from the first block. It's always
+// two digits (06, not 6), as the server and the app name the files that way.
+pub fn peek_state_code<I : Iterator<Item = io::Result<RawBlock>>>(blocks : &mut Peekable<I>) -> io::Result<String> {
+  match blocks.peek() {
+    Some(Ok(block)) => Ok(match block.state_code.parse::<u32>() {
+      Ok(code) => format!("{:02}", code),
+      Err(_) => block.state_code.clone(),
+    }),
+    Some(Err(e)) => Err(invalid_data(format!("Data malformed: {}", e))),
+    None => Err(invalid_data("Data malformed: no blocks found")),
+  }
+}
+
+// Read every block, project it and write its centroid and population out,
+// and its GEOID to `geoids_out`.
+//
+// `project` maps a (lon, lat) ring into cartesian coordinates, which is
+// normally `Projection::project`. `progress` is called with the number of
+// blocks read so far.
+pub fn prepare_blocks<I, W, G, P, F>(
+  blocks : I,
+  state_code : String,
+  out : W,
+  geoids_out : G,
+  options : &PrepOptions,
+  project : P,
+  mut progress : F,
+) -> io::Result<PrepOutput>
+where
+  I : Iterator<Item = io::Result<RawBlock>>,
+  W : Write,
+  G : Write,
+  P : Fn(&[Point<f64>]) -> io::Result<Vec<Point<f64>>>,
+  F : FnMut(usize),
+{
+  let mut writer = BlockWriter::new(out)?;
+  let mut geoids = GeoidWriter::new(geoids_out)?;
+  let mut meta = BlockDataMeta::new(state_code, options.vintage);
+
+  // block outlines are snapped to a 1m grid
+  let mut boundaries = options.boundary_tolerance.map(|_| TopologyBuilder::new(1.));
+  let mut stopped_early = None;
+  let mut unmatched_populations = 0;
+
+  for (n, result) in blocks.enumerate() {
+    progress(n);
+
+    let block = match result {
+      Ok(block) => block,
+      Err(e) => {
+        meta.complete = false;
+        stopped_early = Some(e);
+        break;
+      }
+    };
+
+    meta.blocks_read += 1;
+
+    let population = match block_population(&block, &options.pl_populations) {
+      Some(population) => population,
+      // one block the PL files don't have shouldn't lose the whole state, so
+      // it's counted for a warning and left empty
+      None if options.pl_populations.is_some() => {
+        unmatched_populations += 1;
+        0
+      },
+      None => return Err(invalid_data(format!("Block {} has no population!", block.geoid))),
+    };
+    let rings = block.rings.iter().map(|ring| project(ring)).collect::<io::Result<Vec<_>>>()?;
+    let points : Vec<Point<f64>> = rings.iter().flatten().cloned().collect();
+    let entry = get_block_entry(&points, population);
+    // we don't need entries with no population
+    let index = if entry.2 > 0 {
+      writer.write(&entry)?;
+      geoids.write(&block.geoid)?;
+      Some(writer.count() - 1)
+    } else {
+      None
+    };
+
+    // ...but we do need their outlines
+    if let Some(builder) = boundaries.as_mut() {
+      builder.add(block.geoid, index, &rings);
+    }
+  }
+
+  // with nothing written the bounding box and statistics are still infinite
+  if writer.count() == 0 {
+    return Err(stopped_early.unwrap_or_else(|| invalid_data(format!("No blocks with any population in state {}", meta.state_code))));
+  }
+
+  let meta = writer.finish(meta)?;
+  geoids.finish()?;
+  let topology = match (boundaries, options.boundary_tolerance) {
+    (Some(builder), Some(tolerance)) => Some(builder.build(tolerance)),
+    _ => None,
+  };
+
+  Ok(PrepOutput { meta, topology, stopped_early, unmatched_populations })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn square(geoid : &str, x : f64, y : f64, population : u32) -> io::Result<RawBlock> {
+    Ok(RawBlock {
+      geoid: geoid.to_string(),
+      state_code: "37".to_string(),
+      rings: vec![vec![
+        Point::new(x, y),
+        Point::new(x + 2., y),
+        Point::new(x + 2., y + 2.),
+        Point::new(x, y + 2.),
+      ]],
+      population: Some(population),
+    })
+  }
+
+  fn identity(ring : &[Point<f64>]) -> io::Result<Vec<Point<f64>>> {
+    Ok(ring.to_vec())
+  }
+
+  #[test]
+  fn test_centroid() {
+    assert_eq!(get_centroid(&[]), (0., 0.));
+    let points = vec![Point::new(0., 0.), Point::new(4., 0.), Point::new(4., 2.), Point::new(0., 2.)];
+    assert_eq!(get_centroid(&points), (2., 1.));
+  }
+
+  #[test]
+  fn test_prepare_blocks() {
+    let blocks = vec![
+      square("370010001001000", 0., 0., 10),
+      square("370010001001001", 2., 0., 0),
+      square("370010001001002", 4., 0., 5),
+    ];
+    let mut out = vec![];
+    let mut geoids = vec![];
+    let options = PrepOptions { boundary_tolerance: Some(0.), ..PrepOptions::default() };
+    let output = prepare_blocks(blocks.into_iter(), "37".to_string(), &mut out, &mut geoids, &options, identity, |_| {}).unwrap();
+
+    assert_eq!(output.meta.state_name, Some("North Carolina"));
+    assert_eq!(output.meta.blocks_read, 3);
+    assert_eq!(output.meta.block_count, 2);
+    assert_eq!(output.meta.total_population, 15);
+    assert_eq!(output.meta.bounding_box, BoundingBox { min_x: 1., min_y: 1., max_x: 5., max_y: 1. });
+
+    let written : serde_json::Value = serde_json::from_slice(&out).unwrap();
+    assert_eq!(written["blocks"], serde_json::json!([[1., 1., 10], [5., 1., 5]]));
+    assert_eq!(written["meta"]["block_count"], 2);
+    let geoids : Vec<String> = serde_json::from_slice(&geoids).unwrap();
+    assert_eq!(geoids, vec!["370010001001000", "370010001001002"]);
+
+    // the empty block in the middle touches both of its neighbours
+    let topology = output.topology.unwrap();
+    assert_eq!(topology.adjacency(), vec![(0, 1), (1, 2)]);
+    // but it isn't in the block data, and the blocks either side don't touch
+    assert_eq!(topology.block_adjacency(), vec![]);
+  }
+
+  #[test]
+  fn test_pending_file() {
+    let dir = std::env::temp_dir().join(format!("data_prep_pending_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("block_data_state_37.json");
+
+    // dropped without a commit, nothing is left behind
+    {
+      let (_pending, mut file) = PendingFile::create(&path).unwrap();
+      file.write_all(b"{\"blocks\":[").unwrap();
+    }
+    assert!(!path.exists());
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+    let (pending, mut file) = PendingFile::create(&path).unwrap();
+    file.write_all(b"{}").unwrap();
+    drop(file);
+    pending.commit().unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "{}");
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn test_state_code_is_two_digits() {
+    let mut block = square("060010001001000", 0., 0., 1).unwrap();
+    block.state_code = "6".to_string();
+    let mut blocks = vec![Ok(block)].into_iter().peekable();
+    assert_eq!(peek_state_code(&mut blocks).unwrap(), "06");
+  }
+
+  #[test]
+  fn test_pl_populations_take_precedence() {
+    let mut populations = HashMap::new();
+    populations.insert("370010001001000".to_string(), 7);
+    let block = square("370010001001000", 0., 0., 10).unwrap();
+
+    assert_eq!(block_population(&block, &None), Some(10));
+    assert_eq!(block_population(&block, &Some(populations.clone())), Some(7));
+
+    populations.clear();
+    assert_eq!(block_population(&block, &Some(populations)), None);
+  }
+
+  #[test]
+  fn test_unmatched_populations() {
+    let mut populations = HashMap::new();
+    populations.insert("370010001001000".to_string(), 7);
+    let blocks = vec![square("370010001001000", 0., 0., 10), square("370010001001001", 2., 0., 10)];
+    let options = PrepOptions { pl_populations: Some(populations), ..PrepOptions::default() };
+    let output = prepare_blocks(blocks.into_iter(), "37".to_string(), vec![], vec![], &options, identity, |_| {}).unwrap();
+    assert_eq!(output.unmatched_populations, 1);
+    assert_eq!(output.meta.block_count, 1);
+    assert_eq!(output.meta.total_population, 7);
+  }
+
+  #[test]
+  fn test_empty_state() {
+    let blocks = vec![square("370010001001000", 0., 0., 0)];
+    let result = prepare_blocks(blocks.into_iter(), "37".to_string(), vec![], vec![], &PrepOptions::default(), identity, |_| {});
+    assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidData);
+  }
+}
@@ -1,120 +1,130 @@
-use std::fs::File;
-use serde::{Serialize, Deserialize};
+use std::io::BufWriter;
 use std::env;
-use std::collections::HashMap;
-use shapefile::{
-  dbase::FieldValue,
-  record::{Shape}
-};
 
-
-use proj::Proj;
-extern crate geo_types;
-use geo_types::Point;
+use data_prep::{pl94, InputFormat, PendingFile, PrepOptions, Projection};
 
 // use rustbreak::Database;
 use indicatif::ProgressBar;
 
-#[derive(Debug, Serialize, Deserialize)]
-struct BlockEntry(f64, f64, u32);
-
-// Get the centroid of a _cartesian_ polygon
-fn get_centroid(polygon : Vec<Point<f64>>) -> (f64, f64) {
-  let mut x = 0.;
-  let mut y = 0.;
-  let l = polygon.len() as f64;
-
-  if l == 0. {
-    return (x, y);
-  }
-
-  for p in polygon {
-    x += p.x();
-    y += p.y();
-  }
-
-  x /= l;
-  y /= l;
-
-  return (x, y);
+fn usage() {
+  println!("Usage: data-prep <input> [--format tiger2010|tiger2020|geojson] [--pl <geo file> <segment 1 file>] [--boundaries <tolerance in metres>] [--vintage <year>]");
+  println!();
+  println!("  --boundaries keeps every block outline in memory until the end, so it needs");
+  println!("  a lot more memory than the block data alone on a big state.");
 }
 
-// get the block entry by getting the centroid of the cartesian polygon
-fn get_block_entry(polygon : Vec<Point<f64>>, record : HashMap<std::string::String, FieldValue>) -> BlockEntry {
-  let (x, y) = get_centroid(polygon);
+struct Args {
+  input: String,
+  format: Option<InputFormat>,
+  pl_files: Option<(String, String)>,
+  boundary_tolerance: Option<f64>,
+  vintage: Option<u32>,
+}
 
-  if let FieldValue::Numeric(Some(population)) = &record["POP10"] {
-    BlockEntry(x, y, *population as u32)
-  } else {
-    panic!("Block has no population field!");
+fn parse_args(args : &[String]) -> Option<Args> {
+  let mut input = None;
+  let mut format = None;
+  let mut pl_files = None;
+  let mut boundary_tolerance = None;
+  let mut vintage = None;
+  let mut iter = args.iter().skip(1);
+
+  while let Some(arg) = iter.next() {
+    match arg.as_str() {
+      "--format" => format = Some(InputFormat::from_name(iter.next()?)?),
+      "--pl" => pl_files = Some((iter.next()?.clone(), iter.next()?.clone())),
+      "--boundaries" => boundary_tolerance = Some(iter.next()?.parse().ok()?),
+      "--vintage" => vintage = Some(iter.next()?.parse().ok()?),
+      _ if input.is_none() => input = Some(arg.clone()),
+      _ => return None,
+    }
   }
+
+  Some(Args { input: input?, format, pl_files, boundary_tolerance, vintage })
 }
 
 fn main() -> std::io::Result<()> {
   let args : Vec<String> = env::args().collect();
-  if args.len() < 2 {
-    println!("Please provide a shapefile as the first argument");
-    return Ok(());
-  }
+  let args = match parse_args(&args) {
+    Some(args) => args,
+    None => {
+      usage();
+      return Ok(());
+    }
+  };
 
-  let reader = shapefile::Reader::from_path(&args[1]).unwrap();
-  let mut iter = reader.iter_shapes_and_records().unwrap().peekable();
+  let format = match args.format {
+    Some(format) => format,
+    None => InputFormat::detect(&args.input)?,
+  };
 
-  let state_code;
-  {
-    let result = iter.peek();
-    let (_shape, record) = result.unwrap().as_ref().unwrap();
+  let options = PrepOptions {
+    pl_populations: match &args.pl_files {
+      Some((geo, data)) => Some(pl94::read_populations(geo, data)?),
+      None => None,
+    },
+    boundary_tolerance: args.boundary_tolerance,
+    vintage: args.vintage.or_else(|| format.vintage()),
+  };
 
-    if let FieldValue::Character(Some(code)) = &record["STATEFP10"] {
-      state_code = code;
-    } else {
-      panic!("Data malformed");
-    };
-  }
+  let mut blocks = data_prep::read_blocks(&args.input, format)?.peekable();
+  let state_code = data_prep::peek_state_code(&mut blocks)?;
 
   // let db = Database::<usize>::open(format!("block_data_state_{}", state_code)).unwrap();
-  let outfile = File::create(format!("block_data_state_{}.json", state_code))?;
-  let mut entries = Vec::new();
+  // nothing is moved into place until everything is written
+  let (block_data, outfile) = PendingFile::create(format!("block_data_state_{}.json", state_code))?;
+  // so block assignments can be exported by GEOID
+  let (block_geoids, geoidfile) = PendingFile::create(format!("block_geoids_state_{}.json", state_code))?;
 
   // store the block entries as this more readable format
   let spinner = ProgressBar::new_spinner();
   spinner.enable_steady_tick(100);
 
   // setup an equal area projection
-  let projection = Proj::new(
-    "
-    +proj=pipeline
-    +step +proj=cea
-    "
-  ).unwrap();
-
-  for (n, result) in iter.enumerate() {
-    let (shape, record) = result.unwrap();
-
-    spinner.set_message(&format!("{} blocks read", n));
-
-    if let Shape::Polygon(s) = shape {
-      let mut points : Vec<Point<f64>> = s.points.iter().map(|p| {
-        Point::new(p.x.to_radians(), p.y.to_radians())
-      }).collect();
-      projection.project_array(&mut points, false).unwrap();
-      let entry = get_block_entry(points, record);
-      // we don't need entries with no population
-      if entry.2 > 0 {
-        entries.push(entry);
-        // db.insert(&n, entry).unwrap();
-      }
-    } else {
-      println!("Stopped early! Found something that is not a polygon: {}", shape);
-      return Ok(());
-    }
+  let projection = Projection::equal_area()?;
+
+  let output = data_prep::prepare_blocks(
+    blocks,
+    state_code.clone(),
+    BufWriter::new(outfile),
+    BufWriter::new(geoidfile),
+    &options,
+    |ring| projection.project(ring),
+    |n| spinner.set_message(&format!("{} blocks read", n)),
+  )?;
+
+  // a partial state would look like a whole one to the app, so leave the
+  // old files alone (the pending ones are removed as they're dropped)
+  if let Some(e) = output.stopped_early {
+    spinner.finish();
+    println!("Stopped early after {} blocks, nothing was written", output.meta.block_count);
+    return Err(e);
+  }
+
+  if output.unmatched_populations > 0 {
+    println!("Warning: {} blocks weren't in the PL data and were left with no population", output.unmatched_populations);
   }
 
-  // db.flush().unwrap();
-  serde_json::to_writer(outfile, &entries)?;
+  if let Some(topology) = &output.topology {
+    let (boundaries, topofile) = PendingFile::create(format!("block_boundaries_state_{}.topo.json", state_code))?;
+    serde_json::to_writer(BufWriter::new(topofile), topology)?;
+    boundaries.commit()?;
+
+    // flat block index pairs, for Redistricter.set_block_adjacency
+    let pairs : Vec<usize> = topology.block_adjacency().into_iter().flat_map(|(a, b)| vec![a, b]).collect();
+    let (adjacency, adjacencyfile) = PendingFile::create(format!("block_adjacency_state_{}.json", state_code))?;
+    serde_json::to_writer(BufWriter::new(adjacencyfile), &pairs)?;
+    adjacency.commit()?;
+  }
+  block_data.commit()?;
+  block_geoids.commit()?;
 
   spinner.finish();
-  println!("Done!");
+  println!(
+    "Done! Wrote {} blocks with a total population of {}",
+    output.meta.block_count,
+    output.meta.total_population
+  );
 
   Ok(())
 }
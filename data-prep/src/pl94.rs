@@ -0,0 +1,177 @@
+// Reading block populations out of the PL 94-171 redistricting data files.
+//
+// The PL data comes as a geographic header file plus numbered segment files,
+// joined on LOGRECNO. 2020 files are pipe-delimited, 2010 geo headers are
+// fixed-width and their segments are comma-delimited. P0010001 (total
+// population) is the first table cell of segment 1. The files are Latin-1
+// (place names like Doña Ana), not UTF-8.
+//
+// https://www.census.gov/programs-surveys/decennial-census/about/rdo/summary-files.html
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+// summary level for census blocks
+const BLOCK_SUMLEV : &str = "750";
+
+fn invalid_data<E : ToString>(e : E) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+// Lines of a Latin-1 file. Every byte is the char with the same number, so
+// columns still line up with chars.
+fn latin1_lines<R : BufRead>(mut reader : R) -> impl Iterator<Item = io::Result<String>> {
+  let mut buf = vec![];
+  std::iter::from_fn(move || {
+    buf.clear();
+    match reader.read_until(b'\n', &mut buf) {
+      Ok(0) => None,
+      Ok(_) => {
+        while buf.last() == Some(&b'\n') || buf.last() == Some(&b'\r') {
+          buf.pop();
+        }
+        Some(Ok(buf.iter().map(|&b| b as char).collect()))
+      },
+      Err(e) => Some(Err(e)),
+    }
+  })
+}
+
+// 1-indexed, inclusive column ranges of the 2010 fixed-width geo header
+fn fixed_width(line : &str, start : usize, end : usize) -> Option<String> {
+  let field : String = line.chars().skip(start - 1).take(end + 1 - start).collect();
+  if field.chars().count() < end + 1 - start {
+    return None;
+  }
+  Some(field.trim().to_string())
+}
+
+// Get the (LOGRECNO, GEOID) of a block from a line of the geo header, if it is a block
+fn parse_geo_line(line : &str) -> io::Result<Option<(String, String)>> {
+  if line.contains('|') {
+    // 2020: FILEID|STUSAB|SUMLEV|GEOVAR|GEOCOMP|CHARITER|CIFSN|LOGRECNO|GEOID|GEOCODE|...
+    let fields : Vec<&str> = line.split('|').collect();
+    if fields.len() < 10 {
+      return Err(invalid_data(format!("Geo header line has too few fields: {}", line)));
+    }
+    if fields[2] != BLOCK_SUMLEV {
+      return Ok(None);
+    }
+    return Ok(Some((fields[7].to_string(), fields[9].to_string())));
+  }
+
+  // 2010: SUMLEV 9-11, LOGRECNO 19-25, STATE 28-29, COUNTY 30-32, TRACT 55-60, BLOCK 62-65
+  let field = |start, end| fixed_width(line, start, end)
+    .ok_or_else(|| invalid_data(format!("Geo header line is too short: {}", line)));
+
+  if field(9, 11)? != BLOCK_SUMLEV {
+    return Ok(None);
+  }
+
+  let geoid = format!("{}{}{}{}", field(28, 29)?, field(30, 32)?, field(55, 60)?, field(62, 65)?);
+  Ok(Some((field(19, 25)?, geoid)))
+}
+
+// Get the (LOGRECNO, P0010001) from a line of segment 1
+fn parse_data_line(line : &str) -> io::Result<(String, u32)> {
+  // FILEID,STUSAB,CHARITER,CIFSN,LOGRECNO,P0010001,...
+  let delimiter = if line.contains('|') { '|' } else { ',' };
+  let fields : Vec<&str> = line.split(delimiter).collect();
+  if fields.len() < 6 {
+    return Err(invalid_data(format!("Segment line has too few fields: {}", line)));
+  }
+
+  let population = fields[5].trim().parse().map_err(|_| {
+    invalid_data(format!("Bad P0010001 value \"{}\" for LOGRECNO {}", fields[5], fields[4]))
+  })?;
+  Ok((fields[4].trim().to_string(), population))
+}
+
+fn read_geo<R : BufRead>(reader : R) -> io::Result<HashMap<String, String>> {
+  let mut blocks = HashMap::new();
+  for line in latin1_lines(reader) {
+    if let Some((logrecno, geoid)) = parse_geo_line(&line?)? {
+      blocks.insert(logrecno, geoid);
+    }
+  }
+  Ok(blocks)
+}
+
+fn join<R : BufRead>(blocks : HashMap<String, String>, reader : R) -> io::Result<HashMap<String, u32>> {
+  let mut populations = HashMap::with_capacity(blocks.len());
+  for line in latin1_lines(reader) {
+    let (logrecno, population) = parse_data_line(&line?)?;
+    if let Some(geoid) = blocks.get(&logrecno) {
+      populations.insert(geoid.clone(), population);
+    }
+  }
+  Ok(populations)
+}
+
+// Build a map from block GEOID to total population
+pub fn read_populations<P : AsRef<Path>>(geo_path : P, data_path : P) -> io::Result<HashMap<String, u32>> {
+  let blocks = read_geo(BufReader::new(File::open(geo_path)?))?;
+  join(blocks, BufReader::new(File::open(data_path)?))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_2020_join() {
+    let geo = "\
+PLST|NC|040|||00|000|0000001|0400000US37|37|||||\n\
+PLST|NC|750|||00|000|0000002|7500000US370010201001000|370010201001000|||||\n\
+PLST|NC|750|||00|000|0000003|7500000US370010201001001|370010201001001|||||\n";
+    let data = "\
+PLST|NC|000|01|0000001|10439388|1\n\
+PLST|NC|000|01|0000002|42|1\n\
+PLST|NC|000|01|0000003|0|0\n";
+
+    let blocks = read_geo(geo.as_bytes()).unwrap();
+    let populations = join(blocks, data.as_bytes()).unwrap();
+
+    assert_eq!(populations.len(), 2);
+    assert_eq!(populations["370010201001000"], 42);
+    assert_eq!(populations["370010201001001"], 0);
+  }
+
+  #[test]
+  fn test_2010_fixed_width() {
+    let mut line = format!("{:<65}", "");
+    line.replace_range(8..11, "750");
+    line.replace_range(18..25, "0000042");
+    line.replace_range(27..29, "37");
+    line.replace_range(29..32, "001");
+    line.replace_range(54..60, "020100");
+    line.replace_range(61..65, "1000");
+
+    let parsed = parse_geo_line(&line).unwrap();
+    assert_eq!(parsed, Some(("0000042".to_string(), "370010201001000".to_string())));
+    assert_eq!(parse_data_line("PLST,NC,000,01,0000042,17").unwrap(), ("0000042".to_string(), 17));
+  }
+
+  #[test]
+  fn test_latin1_names() {
+    // 2020, with the county name (Doña Ana) in Latin-1 after the GEOID
+    let mut geo = b"PLST|NM|750|||00|000|0000002|7500000US350130001001000|350130001001000|Do".to_vec();
+    geo.extend(b"\xf1a Ana County|\r\n");
+    let blocks = read_geo(&geo[..]).unwrap();
+    assert_eq!(blocks["0000002"], "350130001001000");
+
+    // 2010, where the name comes after the fixed-width fields we read
+    let mut line = format!("{:<226}", "").into_bytes();
+    line[8..11].copy_from_slice(b"750");
+    line[18..25].copy_from_slice(b"0000042");
+    line[27..29].copy_from_slice(b"35");
+    line[29..32].copy_from_slice(b"013");
+    line[54..60].copy_from_slice(b"000100");
+    line[61..65].copy_from_slice(b"1000");
+    line.extend(b"Do\xf1a Ana\n");
+    let blocks = read_geo(&line[..]).unwrap();
+    assert_eq!(blocks["0000042"], "350130001001000");
+  }
+}
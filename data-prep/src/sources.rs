@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use serde::Deserialize;
+use serde_json::Value;
+use shapefile::{
+  dbase::FieldValue,
+  record::{Shape}
+};
+use geo_types::Point;
+
+fn invalid_data<E : ToString>(e : E) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+// A census block as read from any of the supported inputs.
+// Coordinates are (lon, lat) in degrees, one ring per polygon part.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawBlock {
+  pub geoid: String,
+  pub state_code: String,
+  pub rings: Vec<Vec<Point<f64>>>,
+  pub population: Option<u32>,
+}
+
+pub type RawBlocks = Box<dyn Iterator<Item = io::Result<RawBlock>>>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputFormat {
+  // TIGER 2010 tabblock shapefiles with POP10 attached
+  Tiger2010,
+  // TIGER 2020 tabblock20 shapefiles
+  Tiger2020,
+  // GeoJSON feature collection of block polygons
+  GeoJson,
+}
+
+impl InputFormat {
+  pub fn from_name(name : &str) -> Option<Self> {
+    match name {
+      "tiger2010" => Some(InputFormat::Tiger2010),
+      "tiger2020" => Some(InputFormat::Tiger2020),
+      "geojson" => Some(InputFormat::GeoJson),
+      _ => None,
+    }
+  }
+
+  // The census year of the data, if the format tells us
+  pub fn vintage(&self) -> Option<u32> {
+    match self {
+      InputFormat::Tiger2010 => Some(2010),
+      InputFormat::Tiger2020 => Some(2020),
+      InputFormat::GeoJson => None,
+    }
+  }
+
+  // Guess the format from the file extension, and for shapefiles, the dbf field names
+  pub fn detect<P : AsRef<Path>>(path : P) -> io::Result<Self> {
+    let path = path.as_ref();
+    match path.extension().and_then(|e| e.to_str()) {
+      Some("json") | Some("geojson") => return Ok(InputFormat::GeoJson),
+      _ => {}
+    }
+
+    let reader = shapefile::Reader::from_path(path).map_err(invalid_data)?;
+    let first = reader.iter_shapes_and_records().map_err(invalid_data)?.next();
+    match first {
+      Some(Ok((_shape, record))) if record.contains_key("STATEFP20") => Ok(InputFormat::Tiger2020),
+      Some(Ok(_)) => Ok(InputFormat::Tiger2010),
+      Some(Err(e)) => Err(invalid_data(e)),
+      None => Err(invalid_data("Shapefile has no records")),
+    }
+  }
+}
+
+// Attribute names used by each TIGER vintage
+struct TigerFields {
+  state: &'static str,
+  geoid: &'static str,
+  population: &'static str,
+}
+
+const TIGER_2010 : TigerFields = TigerFields { state: "STATEFP10", geoid: "BLOCKID10", population: "POP10" };
+const TIGER_2020 : TigerFields = TigerFields { state: "STATEFP20", geoid: "GEOID20", population: "POP20" };
+
+pub fn read_blocks<P : AsRef<Path>>(path : P, format : InputFormat) -> io::Result<RawBlocks> {
+  match format {
+    InputFormat::Tiger2010 => read_shapefile(path, &TIGER_2010),
+    InputFormat::Tiger2020 => read_shapefile(path, &TIGER_2020),
+    InputFormat::GeoJson => read_geojson(path),
+  }
+}
+
+fn character_field(record : &HashMap<String, FieldValue>, name : &str) -> io::Result<String> {
+  match record.get(name) {
+    Some(FieldValue::Character(Some(value))) => Ok(value.trim().to_string()),
+    _ => Err(invalid_data(format!("Block has no {} field!", name))),
+  }
+}
+
+fn numeric_field(record : &HashMap<String, FieldValue>, name : &str) -> Option<u32> {
+  match record.get(name) {
+    Some(FieldValue::Numeric(Some(value))) => Some(*value as u32),
+    Some(FieldValue::Double(value)) => Some(*value as u32),
+    Some(FieldValue::Integer(value)) => Some(*value as u32),
+    _ => None,
+  }
+}
+
+// split the shapefile's flat point list into its parts
+fn shape_rings(points : &[shapefile::Point], parts : &[i32]) -> Vec<Vec<Point<f64>>> {
+  let mut starts : Vec<usize> = parts.iter().map(|&p| p as usize).collect();
+  if starts.is_empty() {
+    starts.push(0);
+  }
+  starts.iter().enumerate().map(|(i, &start)| {
+    let end = starts.get(i + 1).cloned().unwrap_or(points.len());
+    points[start..end].iter().map(|p| Point::new(p.x, p.y)).collect()
+  }).collect()
+}
+
+fn read_shapefile<P : AsRef<Path>>(path : P, fields : &'static TigerFields) -> io::Result<RawBlocks> {
+  let reader = shapefile::Reader::from_path(path).map_err(invalid_data)?;
+  let iter = reader.iter_shapes_and_records().map_err(invalid_data)?;
+
+  Ok(Box::new(iter.map(move |result| {
+    let (shape, record) = result.map_err(invalid_data)?;
+    if let Shape::Polygon(s) = shape {
+      Ok(RawBlock {
+        geoid: character_field(&record, fields.geoid)?,
+        state_code: character_field(&record, fields.state)?,
+        rings: shape_rings(&s.points, &s.parts),
+        population: numeric_field(&record, fields.population),
+      })
+    } else {
+      Err(invalid_data(format!("Found something that is not a polygon: {}", shape)))
+    }
+  })))
+}
+
+// Only the parts of GeoJSON that we care about
+#[derive(Debug, Deserialize)]
+struct FeatureCollection {
+  features: Vec<Feature>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Feature {
+  geometry: Option<Geometry>,
+  #[serde(default)]
+  properties: HashMap<String, Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Geometry {
+  #[serde(rename = "type")]
+  kind: String,
+  coordinates: Value,
+}
+
+const GEOID_PROPERTIES : [&str; 4] = ["GEOID20", "GEOID10", "BLOCKID10", "GEOID"];
+const STATE_PROPERTIES : [&str; 3] = ["STATEFP20", "STATEFP10", "STATEFP"];
+const POPULATION_PROPERTIES : [&str; 5] = ["POP20", "POP10", "P0010001", "POPULATION", "population"];
+
+fn find_property<'a>(properties : &'a HashMap<String, Value>, names : &[&str]) -> Option<&'a Value> {
+  names.iter().filter_map(|name| properties.get(*name)).find(|v| !v.is_null())
+}
+
+fn property_string(value : &Value) -> Option<String> {
+  match value {
+    Value::String(s) => Some(s.trim().to_string()),
+    Value::Number(n) => Some(n.to_string()),
+    _ => None,
+  }
+}
+
+fn property_u32(value : &Value) -> Option<u32> {
+  match value {
+    Value::Number(n) => n.as_f64().map(|n| n as u32),
+    Value::String(s) => s.trim().parse().ok(),
+    _ => None,
+  }
+}
+
+fn ring_points(ring : &[Vec<f64>]) -> io::Result<Vec<Point<f64>>> {
+  ring.iter().map(|c| {
+    if c.len() < 2 {
+      Err(invalid_data("GeoJSON position has fewer than two coordinates"))
+    } else {
+      Ok(Point::new(c[0], c[1]))
+    }
+  }).collect()
+}
+
+fn geometry_rings(geometry : Geometry) -> io::Result<Vec<Vec<Point<f64>>>> {
+  let polygons : Vec<Vec<Vec<Vec<f64>>>> = match geometry.kind.as_str() {
+    "Polygon" => vec![serde_json::from_value(geometry.coordinates)?],
+    "MultiPolygon" => serde_json::from_value(geometry.coordinates)?,
+    other => return Err(invalid_data(format!("Found something that is not a polygon: {}", other))),
+  };
+
+  polygons.iter().flatten().map(|ring| ring_points(ring)).collect()
+}
+
+fn feature_to_block(feature : Feature) -> io::Result<RawBlock> {
+  let properties = &feature.properties;
+  let geoid = find_property(properties, &GEOID_PROPERTIES)
+    .and_then(property_string)
+    .ok_or_else(|| invalid_data("Feature has no GEOID property!"))?;
+  // the state fips code is always the first two digits of a block geoid
+  let state_code = find_property(properties, &STATE_PROPERTIES)
+    .and_then(property_string)
+    .unwrap_or_else(|| geoid.chars().take(2).collect());
+  let population = find_property(properties, &POPULATION_PROPERTIES).and_then(property_u32);
+  let geometry = feature.geometry.ok_or_else(|| invalid_data(format!("Feature {} has no geometry", geoid)))?;
+
+  Ok(RawBlock {
+    geoid,
+    state_code,
+    rings: geometry_rings(geometry)?,
+    population,
+  })
+}
+
+// GeoJSON isn't streamable with serde, so the whole collection is parsed up front
+fn read_geojson<P : AsRef<Path>>(path : P) -> io::Result<RawBlocks> {
+  let file = File::open(path)?;
+  let collection : FeatureCollection = serde_json::from_reader(BufReader::new(file))?;
+  Ok(Box::new(collection.features.into_iter().map(feature_to_block)))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_geojson_feature() {
+    let feature : Feature = serde_json::from_str(r#"{
+      "type": "Feature",
+      "properties": { "GEOID20": "371830501001000", "POP20": 12 },
+      "geometry": {
+        "type": "MultiPolygon",
+        "coordinates": [[[[0, 0], [1, 0], [1, 1], [0, 0]]], [[[2, 2], [3, 2], [3, 3], [2, 2]]]]
+      }
+    }"#).unwrap();
+
+    let block = feature_to_block(feature).unwrap();
+    assert_eq!(block.geoid, "371830501001000");
+    assert_eq!(block.state_code, "37");
+    assert_eq!(block.population, Some(12));
+    assert_eq!(block.rings.len(), 2);
+    assert_eq!(block.rings.iter().flatten().count(), 8);
+  }
+
+  #[test]
+  fn test_geojson_rejects_points() {
+    let feature : Feature = serde_json::from_str(r#"{
+      "properties": { "GEOID": "371830501001000" },
+      "geometry": { "type": "Point", "coordinates": [0, 0] }
+    }"#).unwrap();
+
+    assert!(feature_to_block(feature).is_err());
+  }
+}
@@ -0,0 +1,507 @@
+// Builds a TopoJSON topology out of block polygons.
+//
+// Block rings are quantized, then cut at junctions (points where more than two
+// boundaries meet) into arcs. Arcs shared by neighbouring blocks are stored once,
+// so simplifying each arc with its endpoints fixed keeps neighbours sharing the
+// exact same boundary where they meet. Simplified arcs aren't checked against
+// each other though, so with a tolerance that's large next to the blocks they
+// can cross. Rings are kept to at least three distinct points.
+//
+// Every block outline is held in memory until the topology is built, so this
+// doesn't stream like the block data does.
+//
+// https://github.com/topojson/topojson-specification
+
+use std::collections::{HashMap, HashSet};
+use serde::Serialize;
+use geo_types::Point;
+use redistrict_core::geometry::polygon_indices;
+
+pub type Coord = (i64, i64);
+
+// An arc reference as in TopoJSON: i for arc i, !i (-i - 1) for arc i reversed
+pub type ArcRef = i64;
+
+struct BlockPolygon {
+  id: String,
+  block: Option<usize>,
+  rings: Vec<Vec<Coord>>,
+}
+
+pub struct TopologyBuilder {
+  quantum: f64,
+  polygons: Vec<BlockPolygon>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Transform {
+  pub scale: [f64; 2],
+  pub translate: [f64; 2],
+}
+
+#[derive(Debug, Serialize)]
+pub struct GeometryProperties {
+  // index into the block data file, if the block was written there
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub block: Option<usize>,
+}
+
+// A Polygon is an outer ring followed by its holes. Blocks in more than one
+// piece are MultiPolygons, a list of those.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum GeometryArcs {
+  Polygon(Vec<Vec<ArcRef>>),
+  MultiPolygon(Vec<Vec<Vec<ArcRef>>>),
+}
+
+impl GeometryArcs {
+  pub fn rings(&self) -> Vec<&Vec<ArcRef>> {
+    match self {
+      GeometryArcs::Polygon(rings) => rings.iter().collect(),
+      GeometryArcs::MultiPolygon(polygons) => polygons.iter().flatten().collect(),
+    }
+  }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Geometry {
+  #[serde(rename = "type")]
+  pub kind: &'static str,
+  pub id: String,
+  pub arcs: GeometryArcs,
+  pub properties: GeometryProperties,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GeometryCollection {
+  #[serde(rename = "type")]
+  pub kind: &'static str,
+  pub geometries: Vec<Geometry>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Objects {
+  pub blocks: GeometryCollection,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Topology {
+  #[serde(rename = "type")]
+  pub kind: &'static str,
+  pub bbox: [f64; 4],
+  pub transform: Transform,
+  pub objects: Objects,
+  // delta encoded quantized positions
+  pub arcs: Vec<Vec<Coord>>,
+}
+
+impl Topology {
+  // Pairs of geometries (by index into objects.blocks) that share a boundary arc
+  pub fn adjacency(&self) -> Vec<(usize, usize)> {
+    let mut owners : Vec<Vec<usize>> = vec![vec![]; self.arcs.len()];
+    for (g, geometry) in self.objects.blocks.geometries.iter().enumerate() {
+      for &arc in geometry.arcs.rings().into_iter().flatten() {
+        owners[arc_index(arc)].push(g);
+      }
+    }
+
+    let mut pairs : Vec<(usize, usize)> = owners.iter().flat_map(|o| {
+      o.iter().enumerate().flat_map(move |(i, &a)| {
+        o[i + 1..].iter().filter(move |&&b| b != a).map(move |&b| (a.min(b), a.max(b)))
+      })
+    }).collect();
+    pairs.sort_unstable();
+    pairs.dedup();
+    pairs
+  }
+
+  // Pairs of blocks (by index into the block data) that share a boundary.
+  // Blocks with no population aren't in the block data, so pairs with them
+  // are left out.
+  pub fn block_adjacency(&self) -> Vec<(usize, usize)> {
+    let geometries = &self.objects.blocks.geometries;
+    let mut pairs : Vec<(usize, usize)> = self.adjacency().into_iter()
+      .filter_map(|(a, b)| {
+        let (a, b) = (geometries[a].properties.block?, geometries[b].properties.block?);
+        Some((a.min(b), a.max(b)))
+      })
+      .collect();
+    pairs.sort_unstable();
+    pairs.dedup();
+    pairs
+  }
+}
+
+fn reverse_ref(index : usize) -> ArcRef {
+  !(index as i64)
+}
+
+fn arc_index(arc : ArcRef) -> usize {
+  (if arc < 0 { !arc } else { arc }) as usize
+}
+
+// the open ring (without the repeated closing point), with repeated points removed
+fn open_ring(ring : &[Coord]) -> Vec<Coord> {
+  let mut out : Vec<Coord> = Vec::with_capacity(ring.len());
+  for &c in ring {
+    if out.last() != Some(&c) {
+      out.push(c);
+    }
+  }
+  while out.len() > 1 && out.first() == out.last() {
+    out.pop();
+  }
+  out
+}
+
+fn perpendicular_distance(p : Coord, a : Coord, b : Coord) -> f64 {
+  let (px, py) = (p.0 as f64, p.1 as f64);
+  let (ax, ay) = (a.0 as f64, a.1 as f64);
+  let (bx, by) = (b.0 as f64, b.1 as f64);
+  let dx = bx - ax;
+  let dy = by - ay;
+  let len2 = dx * dx + dy * dy;
+  if len2 == 0. {
+    return ((px - ax).powi(2) + (py - ay).powi(2)).sqrt();
+  }
+  ((px - ax) * dy - (py - ay) * dx).abs() / len2.sqrt()
+}
+
+// Douglas-Peucker simplification that always keeps the end points
+pub fn simplify(points : &[Coord], tolerance : f64) -> Vec<Coord> {
+  if points.len() < 3 || tolerance <= 0. {
+    return points.to_vec();
+  }
+
+  let mut keep = vec![false; points.len()];
+  keep[0] = true;
+  keep[points.len() - 1] = true;
+
+  let mut stack = vec![(0, points.len() - 1)];
+  while let Some((start, end)) = stack.pop() {
+    let mut max_dist = 0.;
+    let mut index = start;
+    for i in (start + 1)..end {
+      let d = perpendicular_distance(points[i], points[start], points[end]);
+      if d > max_dist {
+        max_dist = d;
+        index = i;
+      }
+    }
+
+    if index != start && max_dist > tolerance {
+      keep[index] = true;
+      stack.push((start, index));
+      stack.push((index, end));
+    }
+  }
+
+  points.iter().zip(keep).filter(|(_, k)| *k).map(|(p, _)| *p).collect()
+}
+
+// Simplify an arc. A closed one (a ring with no junctions) keeps the points a
+// third and two thirds of the way round too, so it can't collapse to a line.
+fn simplify_arc(arc : &[Coord], tolerance : f64) -> Vec<Coord> {
+  let n = arc.len();
+  if n < 4 || arc[0] != arc[n - 1] {
+    return simplify(arc, tolerance);
+  }
+  let (a, b) = (n / 3, 2 * n / 3);
+  let mut out = simplify(&arc[..=a], tolerance);
+  out.pop();
+  out.extend(simplify(&arc[a..=b], tolerance));
+  out.pop();
+  out.extend(simplify(&arc[b..], tolerance));
+  out
+}
+
+fn delta_encode(arc : &[Coord], origin : Coord) -> Vec<Coord> {
+  let mut prev = origin;
+  arc.iter().map(|&(x, y)| {
+    let d = (x - prev.0, y - prev.1);
+    prev = (x, y);
+    d
+  }).collect()
+}
+
+struct ArcTable {
+  arcs: Vec<Vec<Coord>>,
+  index: HashMap<Vec<Coord>, usize>,
+}
+
+impl ArcTable {
+  fn new() -> Self {
+    Self { arcs: vec![], index: HashMap::new() }
+  }
+
+  fn insert(&mut self, arc : Vec<Coord>) -> ArcRef {
+    if let Some(&i) = self.index.get(&arc) {
+      return i as ArcRef;
+    }
+    let reversed : Vec<Coord> = arc.iter().rev().cloned().collect();
+    if let Some(&i) = self.index.get(&reversed) {
+      return reverse_ref(i);
+    }
+    let i = self.arcs.len();
+    self.index.insert(arc.clone(), i);
+    self.arcs.push(arc);
+    i as ArcRef
+  }
+}
+
+impl TopologyBuilder {
+  // quantum is the grid size, in projected units, that coordinates are snapped to
+  pub fn new(quantum : f64) -> Self {
+    Self { quantum, polygons: vec![] }
+  }
+
+  pub fn add(&mut self, id : String, block : Option<usize>, rings : &[Vec<Point<f64>>]) {
+    let q = self.quantum;
+    let rings = rings.iter().map(|ring| {
+      ring.iter().map(|p| ((p.x() / q).round() as i64, (p.y() / q).round() as i64)).collect()
+    }).collect();
+    self.polygons.push(BlockPolygon { id, block, rings });
+  }
+
+  // points whose neighbours along the rings aren't the same two points
+  fn junctions<'a, I : Iterator<Item = &'a Vec<Coord>>>(rings : I) -> HashSet<Coord> {
+    let mut neighbours : HashMap<Coord, Vec<Coord>> = HashMap::new();
+    let mut junctions = HashSet::new();
+
+    for ring in rings {
+      let n = ring.len();
+      for i in 0..n {
+        let p = ring[i];
+        if junctions.contains(&p) {
+          continue;
+        }
+        let list = neighbours.entry(p).or_default();
+        for &q in [ring[(i + n - 1) % n], ring[(i + 1) % n]].iter() {
+          if !list.contains(&q) {
+            list.push(q);
+          }
+        }
+        if list.len() > 2 {
+          junctions.insert(p);
+          neighbours.remove(&p);
+        }
+      }
+    }
+
+    junctions
+  }
+
+  // cut an open ring into closed-off arcs at the junctions
+  fn cut_ring(ring : &[Coord], junctions : &HashSet<Coord>) -> Vec<Vec<Coord>> {
+    let n = ring.len();
+    let start = match ring.iter().position(|p| junctions.contains(p)) {
+      Some(i) => i,
+      None => {
+        // no junctions, so the ring is a single arc starting at a canonical point
+        let (i, _) = ring.iter().enumerate().min_by_key(|(_, p)| **p).unwrap();
+        let mut arc : Vec<Coord> = ring[i..].iter().chain(ring[..i].iter()).cloned().collect();
+        arc.push(ring[i]);
+        return vec![arc];
+      }
+    };
+
+    let mut arcs = vec![];
+    let mut arc = vec![ring[start]];
+    for k in 1..=n {
+      let p = ring[(start + k) % n];
+      arc.push(p);
+      if junctions.contains(&p) {
+        arcs.push(arc);
+        arc = vec![p];
+      }
+    }
+    arcs
+  }
+
+  // tolerance is the Douglas-Peucker tolerance in projected units
+  pub fn build(self, tolerance : f64) -> Topology {
+    let q = self.quantum;
+    let polygons : Vec<(String, Option<usize>, Vec<Vec<Coord>>)> = self.polygons.into_iter().map(|p| {
+      let rings = p.rings.iter().map(|r| open_ring(r)).filter(|r| r.len() >= 3).collect();
+      (p.id, p.block, rings)
+    }).collect();
+
+    let junctions = Self::junctions(polygons.iter().flat_map(|(_, _, rings)| rings.iter()));
+
+    let mut table = ArcTable::new();
+    let geometries : Vec<Geometry> = polygons.into_iter().map(|(id, block, rings)| {
+      let mut refs : Vec<Vec<ArcRef>> = rings.iter().map(|ring| {
+        Self::cut_ring(ring, &junctions).into_iter().map(|arc| table.insert(arc)).collect()
+      }).collect();
+      let float_rings : Vec<Vec<(f64, f64)>> = rings.iter()
+        .map(|ring| ring.iter().map(|&(x, y)| (x as f64, y as f64)).collect())
+        .collect();
+      let mut polygons : Vec<Vec<Vec<ArcRef>>> = polygon_indices(&float_rings).into_iter().map(|polygon| {
+        polygon.into_iter().map(|i| std::mem::take(&mut refs[i])).collect()
+      }).collect();
+
+      let (kind, arcs) = if polygons.len() <= 1 {
+        ("Polygon", GeometryArcs::Polygon(polygons.pop().unwrap_or_default()))
+      } else {
+        ("MultiPolygon", GeometryArcs::MultiPolygon(polygons))
+      };
+      Geometry {
+        kind,
+        id,
+        arcs,
+        properties: GeometryProperties { block },
+      }
+    }).collect();
+
+    let mut arcs : Vec<Vec<Coord>> = table.arcs.iter().map(|arc| simplify_arc(arc, tolerance / q)).collect();
+
+    // a ring whose arcs simplified down to less than a triangle gets them back
+    // as they were
+    for geometry in &geometries {
+      for ring in geometry.arcs.rings() {
+        let points : usize = ring.iter().map(|&arc| arcs[arc_index(arc)].len() - 1).sum();
+        if points < 3 {
+          for &arc in ring {
+            arcs[arc_index(arc)] = table.arcs[arc_index(arc)].clone();
+          }
+        }
+      }
+    }
+
+    let min_x = arcs.iter().flatten().map(|c| c.0).min().unwrap_or(0);
+    let min_y = arcs.iter().flatten().map(|c| c.1).min().unwrap_or(0);
+    let max_x = arcs.iter().flatten().map(|c| c.0).max().unwrap_or(0);
+    let max_y = arcs.iter().flatten().map(|c| c.1).max().unwrap_or(0);
+
+    Topology {
+      kind: "Topology",
+      bbox: [min_x as f64 * q, min_y as f64 * q, max_x as f64 * q, max_y as f64 * q],
+      transform: Transform {
+        scale: [q, q],
+        translate: [min_x as f64 * q, min_y as f64 * q],
+      },
+      objects: Objects {
+        blocks: GeometryCollection {
+          kind: "GeometryCollection",
+          geometries,
+        },
+      },
+      arcs: arcs.iter().map(|arc| delta_encode(arc, (min_x, min_y))).collect(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn square(x : f64, y : f64) -> Vec<Vec<Point<f64>>> {
+    vec![vec![
+      Point::new(x, y),
+      Point::new(x + 1., y),
+      Point::new(x + 1., y + 1.),
+      Point::new(x, y + 1.),
+      Point::new(x, y),
+    ]]
+  }
+
+  #[test]
+  fn test_shared_arcs() {
+    let mut builder = TopologyBuilder::new(1.);
+    builder.add("a".into(), Some(0), &square(0., 0.));
+    builder.add("b".into(), Some(1), &square(1., 0.));
+    let topology = builder.build(0.);
+
+    // two outer arcs and one shared edge
+    assert_eq!(topology.arcs.len(), 3);
+    let a = topology.objects.blocks.geometries[0].arcs.rings()[0];
+    let b = topology.objects.blocks.geometries[1].arcs.rings()[0];
+    let shared : Vec<&ArcRef> = a.iter().filter(|r| b.contains(&!**r)).collect();
+    assert_eq!(shared.len(), 1);
+    assert_eq!(topology.adjacency(), vec![(0, 1)]);
+
+    // by block data index, with a block that's not in the block data
+    let mut builder = TopologyBuilder::new(1.);
+    builder.add("empty".into(), None, &square(0., 0.));
+    builder.add("a".into(), Some(0), &square(1., 0.));
+    builder.add("b".into(), Some(1), &square(2., 0.));
+    let topology = builder.build(0.);
+    assert_eq!(topology.adjacency(), vec![(0, 1), (1, 2)]);
+    assert_eq!(topology.block_adjacency(), vec![(0, 1)]);
+  }
+
+  #[test]
+  fn test_island_is_single_arc() {
+    let mut builder = TopologyBuilder::new(1.);
+    builder.add("a".into(), None, &square(5., 5.));
+    let topology = builder.build(0.);
+
+    assert_eq!(topology.arcs.len(), 1);
+    assert_eq!(topology.arcs[0].len(), 5);
+    assert_eq!(topology.transform.translate, [5., 5.]);
+  }
+
+  #[test]
+  fn test_rings_grouped_into_polygons() {
+    let ring = |x : f64, y : f64, size : f64| vec![
+      Point::new(x, y),
+      Point::new(x + size, y),
+      Point::new(x + size, y + size),
+      Point::new(x, y + size),
+      Point::new(x, y),
+    ];
+
+    let mut builder = TopologyBuilder::new(1.);
+    // a square with a hole, and an island in the hole
+    builder.add("a".into(), Some(0), &[ring(0., 0., 10.), ring(2., 2., 6.), ring(4., 4., 2.)]);
+    builder.add("b".into(), Some(1), &square(20., 0.));
+    let topology = builder.build(0.);
+
+    let a = &topology.objects.blocks.geometries[0];
+    assert_eq!(a.kind, "MultiPolygon");
+    match &a.arcs {
+      GeometryArcs::MultiPolygon(polygons) => {
+        assert_eq!(polygons.len(), 2);
+        assert_eq!(polygons[0].len(), 2);
+        assert_eq!(polygons[1].len(), 1);
+      },
+      _ => panic!("expected a MultiPolygon"),
+    }
+
+    let b = &topology.objects.blocks.geometries[1];
+    assert_eq!(b.kind, "Polygon");
+    let json = serde_json::to_value(b).unwrap();
+    assert_eq!(json["arcs"].as_array().unwrap().len(), 1);
+  }
+
+  #[test]
+  fn test_rings_dont_collapse() {
+    // a thin sliver of an island, which would simplify down to a line
+    let ring : Vec<Point<f64>> = vec![(0., 0.), (10., 0.), (20., 1.), (10., 2.), (0., 0.)]
+      .into_iter().map(|(x, y)| Point::new(x, y)).collect();
+    let mut builder = TopologyBuilder::new(1.);
+    builder.add("a".into(), Some(0), &[ring]);
+    let topology = builder.build(100.);
+    assert!(topology.arcs[0].len() >= 4);
+
+    // two blocks whose arcs each simplify to their ends get them back
+    let mut builder = TopologyBuilder::new(1.);
+    let lens = |dy : f64| vec![(0., 0.), (5., dy), (10., 0.), (5., -dy), (0., 0.)]
+      .into_iter().map(|(x, y)| Point::new(x, y)).collect::<Vec<_>>();
+    builder.add("a".into(), Some(0), &[lens(1.)]);
+    builder.add("b".into(), Some(1), &[vec![Point::new(0., 0.), Point::new(10., 0.), Point::new(10., 10.), Point::new(0., 0.)]]);
+    let topology = builder.build(100.);
+    let points : usize = topology.objects.blocks.geometries[0].arcs.rings()[0].iter()
+      .map(|&arc| topology.arcs[arc_index(arc)].len() - 1)
+      .sum();
+    assert!(points >= 3);
+  }
+
+  #[test]
+  fn test_simplify_keeps_ends() {
+    let line = vec![(0, 0), (1, 1), (2, 0), (3, 0), (4, 10), (5, 0)];
+    assert_eq!(simplify(&line, 2.), vec![(0, 0), (3, 0), (4, 10), (5, 0)]);
+    assert_eq!(simplify(&line, 0.), line);
+  }
+}
@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use serde::{Serialize, Deserialize};
+use redistrict_core::solver::BlockEntry;
+
+// The parts of the data-prep metadata the server needs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockDataMeta {
+  pub state_code: String,
+  #[serde(default)]
+  pub state_name: Option<String>,
+  #[serde(default)]
+  pub vintage: Option<u32>,
+  pub block_count: usize,
+  pub total_population: u64,
+}
+
+#[derive(Debug)]
+pub struct BlockData {
+  pub blocks: Vec<BlockEntry>,
+  pub meta: BlockDataMeta,
+}
+
+#[derive(Deserialize)]
+struct BlockDataFile {
+  blocks: Vec<(f64, f64, u32)>,
+  meta: BlockDataMeta,
+}
+
+// data-prep names the files with the two digit FIPS code (06, not 6)
+pub fn block_data_path(data_dir : &Path, state_code : u32) -> PathBuf {
+  data_dir.join(format!("block_data_state_{:02}.json", state_code))
+}
+
+pub fn load(data_dir : &Path, state_code : u32) -> io::Result<BlockData> {
+  let file = File::open(block_data_path(data_dir, state_code))?;
+  let data : BlockDataFile = serde_json::from_reader(BufReader::new(file))?;
+
+  Ok(BlockData {
+    blocks: data.blocks.iter().map(|b| BlockEntry {
+      coords: (b.0, b.1),
+      population: b.2,
+    }).collect(),
+    meta: data.meta,
+  })
+}
+
+// Keeps states in memory once they've been read
+#[derive(Default)]
+pub struct BlockCache {
+  data_dir: PathBuf,
+  states: Mutex<HashMap<u32, Arc<BlockData>>>,
+}
+
+impl BlockCache {
+  pub fn new<P : Into<PathBuf>>(data_dir : P) -> Self {
+    Self {
+      data_dir: data_dir.into(),
+      states: Mutex::new(HashMap::new()),
+    }
+  }
+
+  pub fn data_dir(&self) -> &Path {
+    &self.data_dir
+  }
+
+  pub fn get(&self, state_code : u32) -> io::Result<Arc<BlockData>> {
+    if let Some(data) = self.states.lock().unwrap().get(&state_code) {
+      return Ok(data.clone());
+    }
+
+    // don't hold the lock while reading a big file
+    let data = Arc::new(load(&self.data_dir, state_code)?);
+    self.states.lock().unwrap().insert(state_code, data.clone());
+    Ok(data)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_paths_use_two_digit_codes() {
+    let dir = Path::new("public");
+    assert_eq!(block_data_path(dir, 6), dir.join("block_data_state_06.json"));
+    assert_eq!(block_data_path(dir, 37), dir.join("block_data_state_37.json"));
+    assert_eq!(crate::exports::geoids_path(dir, 6), dir.join("block_geoids_state_06.json"));
+    assert_eq!(crate::boundaries::boundaries_path(dir, 6), dir.join("block_boundaries_state_06.topo.json"));
+  }
+}
@@ -0,0 +1,287 @@
+// Block outlines from the TopoJSON topology data-prep writes, and the district
+// outlines they dissolve into.
+//
+// Neighbouring blocks share arcs, so a district's outline is just the arcs
+// used by exactly one of its blocks, stitched back together into rings.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Deserializer};
+use redistrict_core::geometry::Ring;
+
+#[derive(Debug, Deserialize)]
+struct Transform {
+  scale: [f64; 2],
+  translate: [f64; 2],
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct GeometryProperties {
+  #[serde(default)]
+  block: Option<usize>,
+}
+
+// Polygons are lists of rings and MultiPolygons lists of polygons. Only the
+// arcs used matter here, so both come out as one list of rings.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum GeometryArcs {
+  Polygon(Vec<Vec<i64>>),
+  MultiPolygon(Vec<Vec<Vec<i64>>>),
+}
+
+fn deserialize_rings<'de, D : Deserializer<'de>>(deserializer : D) -> Result<Vec<Vec<i64>>, D::Error> {
+  Ok(match GeometryArcs::deserialize(deserializer)? {
+    GeometryArcs::Polygon(rings) => rings,
+    GeometryArcs::MultiPolygon(polygons) => polygons.into_iter().flatten().collect(),
+  })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BlockGeometry {
+  pub id: String,
+  #[serde(deserialize_with = "deserialize_rings")]
+  arcs: Vec<Vec<i64>>,
+  #[serde(default)]
+  properties: GeometryProperties,
+}
+
+#[derive(Debug, Deserialize)]
+struct GeometryCollection {
+  geometries: Vec<BlockGeometry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Objects {
+  blocks: GeometryCollection,
+}
+
+#[derive(Debug, Deserialize)]
+struct TopologyFile {
+  transform: Transform,
+  objects: Objects,
+  arcs: Vec<Vec<(i64, i64)>>,
+}
+
+pub struct Boundaries {
+  pub geometries: Vec<BlockGeometry>,
+  // decoded arcs in projected coordinates
+  arcs: Vec<Ring>,
+}
+
+pub fn boundaries_path(data_dir : &Path, state_code : u32) -> PathBuf {
+  data_dir.join(format!("block_boundaries_state_{:02}.topo.json", state_code))
+}
+
+fn arc_index(arc : i64) -> usize {
+  (if arc < 0 { !arc } else { arc }) as usize
+}
+
+impl Boundaries {
+  pub fn load(data_dir : &Path, state_code : u32) -> io::Result<Self> {
+    let file = File::open(boundaries_path(data_dir, state_code))?;
+    let topology : TopologyFile = serde_json::from_reader(BufReader::new(file))?;
+
+    let [sx, sy] = topology.transform.scale;
+    let [tx, ty] = topology.transform.translate;
+    let arcs = topology.arcs.iter().map(|arc| {
+      let mut x = 0;
+      let mut y = 0;
+      arc.iter().map(|&(dx, dy)| {
+        x += dx;
+        y += dy;
+        (x as f64 * sx + tx, y as f64 * sy + ty)
+      }).collect()
+    }).collect();
+
+    Ok(Self { geometries: topology.objects.blocks.geometries, arcs })
+  }
+
+  // Which district every geometry is in. Blocks with no population weren't
+  // given to the solver, so they join the lowest numbered district they
+  // touch. Anything touching no district at all is left as None.
+  pub fn geometry_districts(&self, assignment : &[u32]) -> Vec<Option<u32>> {
+    let mut districts : Vec<Option<u32>> = self.geometries.iter()
+      .map(|g| g.properties.block.and_then(|b| assignment.get(b).cloned()))
+      .collect();
+
+    let arc_owners = self.arc_owners();
+
+    loop {
+      let mut changed = false;
+      for (g, geometry) in self.geometries.iter().enumerate() {
+        if districts[g].is_some() {
+          continue;
+        }
+        let neighbour = geometry.arcs.iter().flatten()
+          .filter_map(|&arc| arc_owners.get(arc_index(arc)))
+          .flatten()
+          .filter_map(|&other| districts[other])
+          .min();
+        if neighbour.is_some() {
+          districts[g] = neighbour;
+          changed = true;
+        }
+      }
+      if !changed {
+        return districts;
+      }
+    }
+  }
+
+  // the geometries using each arc
+  fn arc_owners(&self) -> Vec<Vec<usize>> {
+    let mut arc_owners : Vec<Vec<usize>> = vec![vec![]; self.arcs.len()];
+    for (g, geometry) in self.geometries.iter().enumerate() {
+      for &arc in geometry.arcs.iter().flatten() {
+        if let Some(owners) = arc_owners.get_mut(arc_index(arc)) {
+          owners.push(g);
+        }
+      }
+    }
+    arc_owners
+  }
+
+  // Pairs of geometries that share an arc
+  pub fn adjacency(&self) -> Vec<(usize, usize)> {
+    let mut pairs : Vec<(usize, usize)> = self.arc_owners().iter().flat_map(|o| {
+      o.iter().enumerate().flat_map(move |(i, &a)| {
+        o[i + 1..].iter().filter(move |&&b| b != a).map(move |&b| (a.min(b), a.max(b)))
+      })
+    }).collect();
+    pairs.sort_unstable();
+    pairs.dedup();
+    pairs
+  }
+
+  // The outline of every district, as rings in projected coordinates
+  pub fn dissolve(&self, districts : &[Option<u32>], num_districts : usize) -> Vec<Vec<Ring>> {
+    // arcs used an odd number of times by a district are on its edge
+    let mut uses : HashMap<(u32, usize), usize> = HashMap::new();
+    for (geometry, district) in self.geometries.iter().zip(districts) {
+      if let Some(d) = district {
+        for &arc in geometry.arcs.iter().flatten() {
+          *uses.entry((*d, arc_index(arc))).or_default() += 1;
+        }
+      }
+    }
+
+    let mut edges : Vec<Vec<usize>> = vec![vec![]; num_districts];
+    for ((d, arc), count) in uses {
+      if count % 2 == 1 && (d as usize) < num_districts {
+        edges[d as usize].push(arc);
+      }
+    }
+
+    edges.into_iter().map(|mut arcs| {
+      arcs.sort_unstable();
+      self.stitch(&arcs)
+    }).collect()
+  }
+
+  // join arcs end to end into closed rings, flipping them as needed
+  fn stitch(&self, arcs : &[usize]) -> Vec<Ring> {
+    let key = |p : (f64, f64)| (p.0.to_bits(), p.1.to_bits());
+    let mut by_end : HashMap<(u64, u64), Vec<usize>> = HashMap::new();
+    for (i, &arc) in arcs.iter().enumerate() {
+      let points = &self.arcs[arc];
+      by_end.entry(key(points[0])).or_default().push(i);
+      by_end.entry(key(points[points.len() - 1])).or_default().push(i);
+    }
+
+    let mut used = vec![false; arcs.len()];
+    let mut rings = vec![];
+    for start in 0..arcs.len() {
+      if used[start] {
+        continue;
+      }
+      used[start] = true;
+      let mut ring : Ring = self.arcs[arcs[start]].clone();
+
+      loop {
+        let end = ring[ring.len() - 1];
+        if ring.len() > 1 && end == ring[0] {
+          break;
+        }
+        let next = by_end.get(&key(end))
+          .and_then(|candidates| candidates.iter().cloned().find(|&i| !used[i]));
+        let next = match next {
+          Some(next) => next,
+          // shouldn't happen with a valid topology, but don't loop forever
+          None => break,
+        };
+        used[next] = true;
+        let points = &self.arcs[arcs[next]];
+        if key(points[0]) == key(end) {
+          ring.extend(points[1..].iter().cloned());
+        } else {
+          ring.extend(points[..points.len() - 1].iter().rev().cloned());
+        }
+      }
+
+      // drop the repeated closing point
+      if ring.len() > 1 && ring[0] == ring[ring.len() - 1] {
+        ring.pop();
+      }
+      if ring.len() >= 3 {
+        rings.push(ring);
+      }
+    }
+    rings
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use redistrict_core::geometry::signed_area;
+
+  // a row of three unit squares a, b and c. b has no population.
+  fn row() -> Boundaries {
+    let geometries = serde_json::from_value(serde_json::json!([
+      { "id": "a", "arcs": [[0, 1]], "properties": { "block": 0 } },
+      { "id": "b", "arcs": [[2, 3, 4, -2]], "properties": {} },
+      { "type": "MultiPolygon", "id": "c", "arcs": [[[5, -4]]], "properties": { "block": 1 } },
+    ])).unwrap();
+
+    let arcs = vec![
+      // around a
+      vec![(1., 1.), (0., 1.), (0., 0.), (1., 0.)],
+      // a | b
+      vec![(1., 0.), (1., 1.)],
+      // top of b
+      vec![(1., 1.), (2., 1.)],
+      // b | c
+      vec![(2., 1.), (2., 0.)],
+      // bottom of b
+      vec![(2., 0.), (1., 0.)],
+      // around c
+      vec![(2., 0.), (3., 0.), (3., 1.), (2., 1.)],
+    ];
+    Boundaries { geometries, arcs }
+  }
+
+  #[test]
+  fn test_unpopulated_blocks_join_a_neighbour() {
+    let boundaries = row();
+    assert_eq!(boundaries.geometry_districts(&[1, 0]), vec![Some(1), Some(0), Some(0)]);
+  }
+
+  #[test]
+  fn test_adjacency() {
+    assert_eq!(row().adjacency(), vec![(0, 1), (1, 2)]);
+  }
+
+  #[test]
+  fn test_dissolve() {
+    let boundaries = row();
+    let districts = boundaries.geometry_districts(&[0, 0]);
+    let outlines = boundaries.dissolve(&districts, 2);
+
+    assert_eq!(outlines[0].len(), 1);
+    assert!((signed_area(&outlines[0][0]).abs() - 6.).abs() < 1e-9);
+    assert!(outlines[1].is_empty());
+  }
+}
@@ -0,0 +1,252 @@
+// Server settings.
+//
+// Every setting can come from a command line flag (`--data-dir`), an
+// environment variable (`REDISTRICT_DATA_DIR`) or a key in a TOML file
+// (`data_dir = "..."`), in that order of precedence. The TOML file is the one
+// given by `--config` or `REDISTRICT_CONFIG`, or `redistrict.toml` if it exists.
+//
+// A mistyped flag or TOML key is an error, but an unknown REDISTRICT_*
+// variable is only warned about: the environment is shared with whatever else
+// is running, so it's not ours to be strict about.
+
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use log::LevelFilter;
+use serde::Deserialize;
+
+use crate::jobs::JobConfig;
+
+const ENV_PREFIX : &str = "REDISTRICT_";
+const DEFAULT_CONFIG_FILE : &str = "redistrict.toml";
+
+pub fn usage() -> String {
+  format!("Usage: server [options]
+
+Options (also settable as {prefix}<NAME> environment variables or in a TOML file):
+  --config <file>             TOML file to read settings from (default {file} if present)
+  --host <address>            address to bind to (default 127.0.0.1)
+  --port <port>               port to listen on (default 3000)
+  --data-dir <dir>            where the block data files are (default public)
+  --dist-dir <dir>            the built frontend (default dist)
+  --state-dir <dir>           where saved plans and job checkpoints are kept (default server-state)
+  --worker-threads <n>        threads serving requests (default: one per core)
+  --job-workers <n>           how many jobs solve at once (default 2)
+  --max-queued-jobs <n>       jobs allowed to wait before new ones are refused (default 16)
+  --job-retention <seconds>   how long finished jobs are kept (default 3600)
+  --shutdown-timeout <secs>   how long running jobs get to finish on shutdown (default 30)
+  --log-level <level>         off, error, warn, info, debug or trace (default info)", prefix = ENV_PREFIX, file = DEFAULT_CONFIG_FILE)
+}
+
+// One source of settings. Anything not set is left to the next source.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Settings {
+  host: Option<IpAddr>,
+  port: Option<u16>,
+  data_dir: Option<PathBuf>,
+  dist_dir: Option<PathBuf>,
+  state_dir: Option<PathBuf>,
+  worker_threads: Option<usize>,
+  job_workers: Option<usize>,
+  max_queued_jobs: Option<usize>,
+  job_retention: Option<u64>,
+  shutdown_timeout: Option<u64>,
+  log_level: Option<String>,
+}
+
+fn parse<T : FromStr>(key : &str, value : &str) -> Result<Option<T>, String> {
+  value.trim().parse().map(Some).map_err(|_| format!("Invalid value for {}: {}", key, value))
+}
+
+impl Settings {
+  // false if there's no such setting
+  fn set(&mut self, key : &str, value : &str) -> Result<bool, String> {
+    match key {
+      "host" => self.host = parse(key, value)?,
+      "port" => self.port = parse(key, value)?,
+      "data_dir" => self.data_dir = Some(value.into()),
+      "dist_dir" => self.dist_dir = Some(value.into()),
+      "state_dir" => self.state_dir = Some(value.into()),
+      "worker_threads" => self.worker_threads = parse(key, value)?,
+      "job_workers" => self.job_workers = parse(key, value)?,
+      "max_queued_jobs" => self.max_queued_jobs = parse(key, value)?,
+      "job_retention" => self.job_retention = parse(key, value)?,
+      "shutdown_timeout" => self.shutdown_timeout = parse(key, value)?,
+      "log_level" => self.log_level = Some(value.to_string()),
+      _ => return Ok(false),
+    }
+    Ok(true)
+  }
+
+  // fill in anything unset from the other settings
+  fn or(self, other : Settings) -> Settings {
+    Settings {
+      host: self.host.or(other.host),
+      port: self.port.or(other.port),
+      data_dir: self.data_dir.or(other.data_dir),
+      dist_dir: self.dist_dir.or(other.dist_dir),
+      state_dir: self.state_dir.or(other.state_dir),
+      worker_threads: self.worker_threads.or(other.worker_threads),
+      job_workers: self.job_workers.or(other.job_workers),
+      max_queued_jobs: self.max_queued_jobs.or(other.max_queued_jobs),
+      job_retention: self.job_retention.or(other.job_retention),
+      shutdown_timeout: self.shutdown_timeout.or(other.shutdown_timeout),
+      log_level: self.log_level.or(other.log_level),
+    }
+  }
+
+  // REDISTRICT_DATA_DIR=... sets data_dir, and so on. Also returns the names
+  // of any REDISTRICT_* variables that aren't settings.
+  fn from_env<I : Iterator<Item = (String, String)>>(vars : I) -> Result<(Self, Vec<String>), String> {
+    let mut settings = Settings::default();
+    let mut ignored = vec![];
+    for (name, value) in vars {
+      if !name.starts_with(ENV_PREFIX) || name == "REDISTRICT_CONFIG" {
+        continue;
+      }
+      if !settings.set(&name[ENV_PREFIX.len()..].to_lowercase(), &value)? {
+        ignored.push(name);
+      }
+    }
+    Ok((settings, ignored))
+  }
+
+  // --data-dir ... sets data_dir, and so on. Also returns the --config file, if given.
+  fn from_args(args : &[String]) -> Result<(Self, Option<PathBuf>), String> {
+    let mut settings = Settings::default();
+    let mut config_file = None;
+    let mut iter = args.iter().skip(1);
+
+    while let Some(arg) = iter.next() {
+      let key = match arg.strip_prefix("--") {
+        Some(key) => key.replace('-', "_"),
+        None => return Err(format!("Unexpected argument {}", arg)),
+      };
+      let value = iter.next().ok_or_else(|| format!("Missing value for {}", arg))?;
+      if key == "config" {
+        config_file = Some(PathBuf::from(value));
+      } else if !settings.set(&key, value)? {
+        return Err(format!("Unknown setting {}", arg));
+      }
+    }
+    Ok((settings, config_file))
+  }
+
+  fn from_file(path : &Path) -> Result<Self, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+    toml::from_str(&text).map_err(|e| format!("Could not parse {}: {}", path.display(), e))
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+  pub addr: SocketAddr,
+  pub data_dir: PathBuf,
+  pub dist_dir: PathBuf,
+  pub state_dir: PathBuf,
+  // None for the tokio default of one per core
+  pub worker_threads: Option<usize>,
+  pub jobs: JobConfig,
+  pub shutdown_timeout: Duration,
+  pub log_level: LevelFilter,
+  // REDISTRICT_* variables that aren't settings, to warn about once logging is up
+  pub ignored_env: Vec<String>,
+}
+
+impl Config {
+  fn from_settings(settings : Settings, ignored_env : Vec<String>) -> Result<Self, String> {
+    let defaults = JobConfig::default();
+    let log_level = match settings.log_level {
+      Some(level) => LevelFilter::from_str(&level).map_err(|_| format!("Invalid log level {}", level))?,
+      None => LevelFilter::Info,
+    };
+
+    Ok(Config {
+      addr: SocketAddr::new(
+        settings.host.unwrap_or_else(|| [127, 0, 0, 1].into()),
+        settings.port.unwrap_or(3000)
+      ),
+      data_dir: settings.data_dir.unwrap_or_else(|| "public".into()),
+      dist_dir: settings.dist_dir.unwrap_or_else(|| "dist".into()),
+      state_dir: settings.state_dir.unwrap_or_else(|| "server-state".into()),
+      worker_threads: settings.worker_threads,
+      jobs: JobConfig {
+        workers: settings.job_workers.unwrap_or(defaults.workers),
+        max_queued: settings.max_queued_jobs.unwrap_or(defaults.max_queued),
+        retention: settings.job_retention.map(Duration::from_secs).unwrap_or(defaults.retention),
+      },
+      shutdown_timeout: Duration::from_secs(settings.shutdown_timeout.unwrap_or(30)),
+      log_level,
+      ignored_env,
+    })
+  }
+
+  // Put the settings together from the command line, environment and config file
+  pub fn load<I : Iterator<Item = (String, String)>>(args : &[String], vars : I) -> Result<Self, String> {
+    let vars : Vec<(String, String)> = vars.collect();
+    let (cli, config_file) = Settings::from_args(args)?;
+    let (env, ignored_env) = Settings::from_env(vars.iter().cloned())?;
+
+    let config_file = config_file.or_else(|| {
+      vars.iter().find(|(name, _)| name == "REDISTRICT_CONFIG").map(|(_, value)| value.into())
+    });
+    let file = match config_file {
+      Some(path) => Settings::from_file(&path)?,
+      None if Path::new(DEFAULT_CONFIG_FILE).exists() => Settings::from_file(Path::new(DEFAULT_CONFIG_FILE))?,
+      None => Settings::default(),
+    };
+
+    Self::from_settings(cli.or(env).or(file), ignored_env)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn args(list : &[&str]) -> Vec<String> {
+    std::iter::once("server").chain(list.iter().cloned()).map(String::from).collect()
+  }
+
+  #[test]
+  fn test_precedence() {
+    let (cli, _) = Settings::from_args(&args(&["--port", "8000"])).unwrap();
+    let (env, ignored) = Settings::from_env(vec![
+      ("REDISTRICT_PORT".to_string(), "9000".to_string()),
+      ("REDISTRICT_DATA_DIR".to_string(), "/srv/data".to_string()),
+      ("REDISTRICT_COLOUR".to_string(), "blue".to_string()),
+      ("HOME".to_string(), "/root".to_string()),
+    ].into_iter()).unwrap();
+    assert_eq!(ignored, vec!["REDISTRICT_COLOUR"]);
+    let file : Settings = toml::from_str("port = 7000\ndata_dir = \"/tmp\"\nhost = \"0.0.0.0\"\njob_workers = 4").unwrap();
+
+    let config = Config::from_settings(cli.or(env).or(file), ignored).unwrap();
+    assert_eq!(config.addr, "0.0.0.0:8000".parse().unwrap());
+    assert_eq!(config.data_dir, PathBuf::from("/srv/data"));
+    assert_eq!(config.jobs.workers, 4);
+    assert_eq!(config.log_level, LevelFilter::Info);
+  }
+
+  #[test]
+  fn test_bad_settings() {
+    assert!(Settings::from_args(&args(&["--port", "lots"])).is_err());
+    assert!(Settings::from_args(&args(&["--colour", "blue"])).is_err());
+    assert!(Settings::from_args(&args(&["--port"])).is_err());
+    assert!(toml::from_str::<Settings>("colour = \"blue\"").is_err());
+    // a known variable with a bad value is still an error
+    assert!(Settings::from_env(vec![("REDISTRICT_PORT".to_string(), "lots".to_string())].into_iter()).is_err());
+
+    let (settings, _) = Settings::from_args(&args(&["--log-level", "chatty"])).unwrap();
+    assert!(Config::from_settings(settings, vec![]).is_err());
+  }
+
+  #[test]
+  fn test_config_flag() {
+    let (settings, file) = Settings::from_args(&args(&["--config", "a.toml", "--job-retention", "60"])).unwrap();
+    assert_eq!(file, Some(PathBuf::from("a.toml")));
+    assert_eq!(settings.job_retention, Some(60));
+  }
+}
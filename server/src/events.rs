@@ -0,0 +1,88 @@
+// Server-sent events for watching a job as it solves.
+//
+// The stream starts with a `status` event carrying the job status, then a
+// `step` event per iteration with the centers, weights and district
+// populations. It ends with one of `done` (carrying the whole plan),
+// `failed` or `cancelled` (carrying the final status).
+//
+// https://html.spec.whatwg.org/multipage/server-sent-events.html
+
+use std::sync::Arc;
+use hyper::{Body, Response, StatusCode};
+use hyper::body::Bytes;
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE};
+use serde::Serialize;
+use tokio::sync::broadcast::RecvError;
+
+use crate::AppState;
+use crate::jobs::{Job, JobEvent, JobState, JobStatus};
+use crate::plans::Plan;
+
+fn format_event<T : Serialize>(name : &str, data : &T) -> Bytes {
+  // serde_json never writes raw newlines, so the data fits on one line
+  let data = serde_json::to_string(data).unwrap_or_else(|_| "null".to_string());
+  Bytes::from(format!("event: {}\ndata: {}\n\n", name, data))
+}
+
+fn final_event(status : &JobStatus, plan : Option<&Plan>) -> Bytes {
+  match (status.state, plan) {
+    (JobState::Done, Some(plan)) => format_event("done", plan),
+    (JobState::Cancelled, _) => format_event("cancelled", status),
+    _ => format_event("failed", status),
+  }
+}
+
+pub fn job_events(state : Arc<AppState>, job : Arc<Job>) -> Response<Body> {
+  // subscribe before looking at the status so we can't miss the end
+  let mut events = job.subscribe();
+  let (mut sender, body) = Body::channel();
+
+  tokio::spawn(async move {
+    let status = job.status();
+    if sender.send_data(format_event("status", &status)).await.is_err() {
+      return;
+    }
+
+    if status.state.is_finished() {
+      let plan = status.plan_id.as_ref().and_then(|id| state.plans.get(id).ok().flatten());
+      let _ = sender.send_data(final_event(&status, plan.as_deref())).await;
+      return;
+    }
+
+    loop {
+      let chunk = match events.recv().await {
+        Ok(JobEvent::Step(step)) => format_event("step", &*step),
+        Ok(JobEvent::Finished(status, plan)) => {
+          let _ = sender.send_data(final_event(&status, plan.as_deref())).await;
+          return;
+        },
+        // a slow client misses some steps, but the next one still tells it where we're at
+        Err(RecvError::Lagged(_)) => continue,
+        Err(RecvError::Closed) => return,
+      };
+
+      // the client went away
+      if sender.send_data(chunk).await.is_err() {
+        return;
+      }
+    }
+  });
+
+  Response::builder()
+    .status(StatusCode::OK)
+    .header(CONTENT_TYPE, "text/event-stream")
+    .header(CACHE_CONTROL, "no-cache")
+    .body(body)
+    .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_format_event() {
+    let event = format_event("step", &serde_json::json!({ "iteration": 3 }));
+    assert_eq!(&event[..], &b"event: step\ndata: {\"iteration\":3}\n\n"[..]);
+  }
+}
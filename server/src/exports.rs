@@ -0,0 +1,271 @@
+// Plans in formats other tools can read:
+//
+// * a block assignment file (BAF), the `GEOID,district` csv legislatures use
+// * GeoJSON of the dissolved districts, with the report as properties
+// * a zipped shapefile of the same
+//
+// The GeoJSON and shapefile also give every district a color number, such that
+// neighbouring districts never have the same one, for styling them.
+//
+// Districts are numbered from 1 in all of them. Block GEOIDs come from the
+// block_geoids file data-prep writes, and outlines from its block boundaries.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, Cursor, Write};
+use std::path::{Path, PathBuf};
+use serde_json::{json, Value};
+use shapefile::dbase::{FieldValue, Record};
+
+use redistrict_core::coloring;
+use redistrict_core::geometry::{self, Ring};
+use crate::boundaries::{self, Boundaries};
+use crate::plans::Plan;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+  Baf,
+  GeoJson,
+  Shapefile,
+}
+
+impl ExportFormat {
+  pub fn from_name(name : &str) -> Option<Self> {
+    match name {
+      "baf" | "csv" => Some(ExportFormat::Baf),
+      "geojson" => Some(ExportFormat::GeoJson),
+      "shapefile" | "shp" => Some(ExportFormat::Shapefile),
+      _ => None,
+    }
+  }
+
+  pub fn content_type(self) -> &'static str {
+    match self {
+      ExportFormat::Baf => "text/csv",
+      ExportFormat::GeoJson => "application/geo+json",
+      ExportFormat::Shapefile => "application/zip",
+    }
+  }
+
+  pub fn file_name(self, plan : &Plan) -> String {
+    match self {
+      ExportFormat::Baf => format!("plan_{}.csv", plan.id),
+      ExportFormat::GeoJson => format!("plan_{}.geojson", plan.id),
+      ExportFormat::Shapefile => format!("plan_{}.zip", plan.id),
+    }
+  }
+}
+
+fn not_found(message : &str) -> io::Error {
+  io::Error::new(io::ErrorKind::NotFound, message)
+}
+
+pub fn geoids_path(data_dir : &Path, state_code : u32) -> PathBuf {
+  data_dir.join(format!("block_geoids_state_{:02}.json", state_code))
+}
+
+pub fn load_geoids(data_dir : &Path, state_code : u32) -> io::Result<Vec<String>> {
+  let file = File::open(geoids_path(data_dir, state_code))?;
+  Ok(serde_json::from_reader(BufReader::new(file))?)
+}
+
+fn load_boundaries(data_dir : &Path, state_code : u32) -> io::Result<Boundaries> {
+  Boundaries::load(data_dir, state_code).map_err(|e| match e.kind() {
+    io::ErrorKind::NotFound => not_found("No block boundaries for that state. Run data-prep with --boundaries."),
+    _ => e,
+  })
+}
+
+// (GEOID, district) for every block
+fn block_districts(data_dir : &Path, plan : &Plan) -> io::Result<Vec<(String, u32)>> {
+  // with boundaries we can place the blocks that have no population too
+  if boundaries::boundaries_path(data_dir, plan.state_code).exists() {
+    let boundaries = Boundaries::load(data_dir, plan.state_code)?;
+    let districts = boundaries.geometry_districts(&plan.assignment);
+    let mut rows : Vec<(String, u32)> = boundaries.geometries.iter().zip(districts)
+      .filter_map(|(g, d)| d.map(|d| (g.id.clone(), d)))
+      .collect();
+    rows.sort();
+    return Ok(rows);
+  }
+
+  let geoids = load_geoids(data_dir, plan.state_code).map_err(|e| match e.kind() {
+    io::ErrorKind::NotFound => not_found("No block GEOIDs for that state. Run data-prep again to write them."),
+    _ => e,
+  })?;
+  if geoids.len() != plan.assignment.len() {
+    return Err(io::Error::new(io::ErrorKind::InvalidData, "The block GEOIDs don't match the plan"));
+  }
+  let mut rows : Vec<(String, u32)> = geoids.into_iter().zip(plan.assignment.iter().cloned()).collect();
+  rows.sort();
+  Ok(rows)
+}
+
+pub fn baf<W : Write>(rows : &[(String, u32)], mut out : W) -> io::Result<()> {
+  writeln!(out, "GEOID,district")?;
+  for (geoid, district) in rows {
+    writeln!(out, "{},{}", geoid, district + 1)?;
+  }
+  Ok(())
+}
+
+// one district's polygons, each an outer ring and its holes
+type Polygons = Vec<Vec<Ring>>;
+
+// every district's polygons in (lon, lat), outer rings counter clockwise, and
+// its color
+fn district_polygons(data_dir : &Path, plan : &Plan) -> io::Result<(Vec<Polygons>, Vec<usize>)> {
+  let boundaries = load_boundaries(data_dir, plan.state_code)?;
+  let districts = boundaries.geometry_districts(&plan.assignment);
+  let outlines = boundaries.dissolve(&districts, plan.config.num_districts);
+
+  let touching = boundaries.adjacency().into_iter()
+    .filter_map(|(a, b)| Some((districts[a]?, districts[b]?)));
+  let colors = coloring::dsatur(&coloring::adjacency_from_pairs(plan.config.num_districts, touching), &[]);
+
+  let polygons = outlines.into_iter().map(|rings| {
+    let rings = rings.into_iter()
+      .map(|ring| ring.into_iter().map(|(x, y)| geometry::unproject(x, y)).collect())
+      .collect();
+    geometry::polygons(rings)
+  }).collect();
+  Ok((polygons, colors))
+}
+
+fn district_properties(plan : &Plan, index : usize, color : usize) -> Value {
+  let district = &plan.report.districts[index];
+  json!({
+    "district": index + 1,
+    "color": color,
+    "population": district.population,
+    "deviation": district.deviation,
+    "num_blocks": district.num_blocks,
+    "moment_of_inertia": district.moment_of_inertia,
+  })
+}
+
+// GeoJSON positions close their rings
+fn closed(ring : &[(f64, f64)]) -> Vec<[f64; 2]> {
+  ring.iter().chain(ring.first()).map(|&(x, y)| [x, y]).collect()
+}
+
+pub fn geojson(plan : &Plan, polygons : &[Vec<Vec<Ring>>], colors : &[usize]) -> Value {
+  let features : Vec<Value> = polygons.iter().enumerate().map(|(i, polygons)| {
+    let coordinates : Vec<Vec<Vec<[f64; 2]>>> = polygons.iter()
+      .map(|rings| rings.iter().map(|r| closed(r)).collect())
+      .collect();
+    json!({
+      "type": "Feature",
+      "properties": district_properties(plan, i, colors[i]),
+      "geometry": { "type": "MultiPolygon", "coordinates": coordinates },
+    })
+  }).collect();
+
+  json!({
+    "type": "FeatureCollection",
+    "properties": {
+      "id": plan.id,
+      "state_code": plan.state_code,
+      "state_name": plan.state_name,
+      "num_districts": plan.config.num_districts,
+      "algorithm": plan.config.algorithm,
+      "seed": plan.config.seed,
+      "total_population": plan.report.total_population,
+      "max_deviation": plan.report.max_deviation,
+      "population_spread_percent": plan.report.population_spread_percent,
+      "num_colors": colors.iter().max().map_or(0, |c| c + 1),
+    },
+    "features": features,
+  })
+}
+
+const WGS84_PRJ : &str = r#"GEOGCS["GCS_WGS_1984",DATUM["D_WGS_1984",SPHEROID["WGS_1984",6378137.0,298.257223563]],PRIMEM["Greenwich",0.0],UNIT["Degree",0.0174532925199433]]"#;
+
+fn shapefile_error(e : shapefile::Error) -> io::Error {
+  io::Error::other(e.to_string())
+}
+
+// a .shp, .shx, .dbf and .prj of the districts, zipped up
+pub fn shapefile_zip(plan : &Plan, polygons : &[Vec<Vec<Ring>>], colors : &[usize]) -> io::Result<Vec<u8>> {
+  let mut shapes = vec![];
+  let mut records = vec![];
+  for (i, polygons) in polygons.iter().enumerate() {
+    // shapefiles want outer rings clockwise, and closed
+    let parts : Vec<Vec<shapefile::Point>> = polygons.iter().flatten().map(|ring| {
+      closed(ring).iter().rev().map(|p| shapefile::Point::new(p[0], p[1])).collect()
+    }).collect();
+    if parts.is_empty() {
+      continue;
+    }
+    shapes.push(shapefile::Polygon::with_parts(parts));
+
+    let district = &plan.report.districts[i];
+    let mut record : Record = HashMap::new();
+    record.insert("DISTRICT".to_string(), FieldValue::Numeric(Some((i + 1) as f64)));
+    record.insert("POPULATION".to_string(), FieldValue::Numeric(Some(district.population as f64)));
+    record.insert("DEVIATION".to_string(), FieldValue::Numeric(Some(district.deviation)));
+    record.insert("COLOR".to_string(), FieldValue::Numeric(Some(colors[i] as f64)));
+    records.push(record);
+  }
+
+  let mut shp = vec![];
+  let mut shx = vec![];
+  let mut dbf = vec![];
+  {
+    let mut writer = shapefile::Writer::new(&mut shp);
+    writer.add_index_dest(&mut shx);
+    writer.add_dbase_dest(&mut dbf);
+    writer.write_shapes_and_records(shapes, records).map_err(shapefile_error)?;
+  }
+
+  let name = format!("plan_{}", plan.id);
+  let mut zip = zip::ZipWriter::new(Cursor::new(vec![]));
+  let options = zip::write::FileOptions::default();
+  for (extension, bytes) in [("shp", &shp[..]), ("shx", &shx[..]), ("dbf", &dbf[..]), ("prj", WGS84_PRJ.as_bytes())].iter() {
+    zip.start_file(format!("{}.{}", name, extension), options)?;
+    zip.write_all(bytes)?;
+  }
+  Ok(zip.finish()?.into_inner())
+}
+
+pub fn export(data_dir : &Path, plan : &Plan, format : ExportFormat) -> io::Result<Vec<u8>> {
+  match format {
+    ExportFormat::Baf => {
+      let mut out = vec![];
+      baf(&block_districts(data_dir, plan)?, &mut out)?;
+      Ok(out)
+    },
+    ExportFormat::GeoJson => {
+      let (polygons, colors) = district_polygons(data_dir, plan)?;
+      Ok(serde_json::to_vec(&geojson(plan, &polygons, &colors))?)
+    },
+    ExportFormat::Shapefile => {
+      let (polygons, colors) = district_polygons(data_dir, plan)?;
+      shapefile_zip(plan, &polygons, &colors)
+    },
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_baf() {
+    let mut out = vec![];
+    baf(&[("370010001001000".to_string(), 0), ("370010001001001".to_string(), 2)], &mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), "GEOID,district\n370010001001000,1\n370010001001001,3\n");
+  }
+
+  #[test]
+  fn test_closed() {
+    assert_eq!(closed(&[(0., 0.), (1., 0.), (1., 1.)]), vec![[0., 0.], [1., 0.], [1., 1.], [0., 0.]]);
+  }
+
+  #[test]
+  fn test_format_names() {
+    assert_eq!(ExportFormat::from_name("csv"), Some(ExportFormat::Baf));
+    assert_eq!(ExportFormat::from_name("shp"), Some(ExportFormat::Shapefile));
+    assert_eq!(ExportFormat::from_name("kml"), None);
+  }
+}
@@ -0,0 +1,355 @@
+// Static file serving for the block data and the built frontend.
+//
+// Compression is done ahead of time: if the client accepts it and there's a
+// `name.br` or `name.gz` next to `name`, that is sent instead. Files get an
+// ETag from their size and modification time, and single byte ranges are
+// supported so big block data files can be fetched in pieces or resumed.
+
+use std::io::{self, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use hyper::{Body, HeaderMap, Method, Request, Response, StatusCode};
+use hyper::body::Bytes;
+use hyper::header::{
+  ACCEPT_ENCODING, ACCEPT_RANGES, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE,
+  CONTENT_TYPE, ETAG, IF_NONE_MATCH, RANGE, VARY,
+};
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+
+const CHUNK_SIZE : usize = 64 * 1024;
+
+// the encodings we look for precompressed files for, best first
+const ENCODINGS : [(&str, &str); 2] = [("br", "br"), ("gzip", "gz")];
+
+pub struct StaticFiles {
+  root: PathBuf,
+  // serve index.html for "/" and directories. Off for the block data, so its
+  // directory can't shadow the frontend's pages.
+  index: bool,
+  // serve index.html for paths that don't match a file, for client side routing
+  spa_fallback: bool,
+}
+
+fn content_type(path : &Path) -> &'static str {
+  match path.extension().and_then(|e| e.to_str()).unwrap_or("") {
+    "html" => "text/html; charset=utf-8",
+    "js" => "application/javascript",
+    "mjs" => "application/javascript",
+    "css" => "text/css",
+    "json" => "application/json",
+    "map" => "application/json",
+    "wasm" => "application/wasm",
+    "svg" => "image/svg+xml",
+    "png" => "image/png",
+    "jpg" | "jpeg" => "image/jpeg",
+    "gif" => "image/gif",
+    "ico" => "image/x-icon",
+    "woff" => "font/woff",
+    "woff2" => "font/woff2",
+    "ttf" => "font/ttf",
+    "eot" => "application/vnd.ms-fontobject",
+    "txt" => "text/plain; charset=utf-8",
+    _ => "application/octet-stream",
+  }
+}
+
+// Map a request path onto the root, refusing anything that could climb out of it
+fn resolve(root : &Path, request_path : &str) -> Option<PathBuf> {
+  let mut path = root.to_path_buf();
+  for component in Path::new(request_path.trim_start_matches('/')).components() {
+    match component {
+      Component::Normal(part) => path.push(part),
+      Component::CurDir => {},
+      _ => return None,
+    }
+  }
+  Some(path)
+}
+
+// Does the client take this content coding? (ignoring ones it gives q=0)
+fn accepts_encoding(headers : &HeaderMap, encoding : &str) -> bool {
+  headers.get_all(ACCEPT_ENCODING).iter()
+    .filter_map(|v| v.to_str().ok())
+    .flat_map(|v| v.split(','))
+    .any(|item| {
+      let mut parts = item.split(';').map(|p| p.trim());
+      let name = parts.next().unwrap_or("");
+      let refused = parts.any(|p| p.replace(' ', "") == "q=0" || p.replace(' ', "") == "q=0.0");
+      (name.eq_ignore_ascii_case(encoding) || name == "*") && !refused
+    })
+}
+
+fn etag(len : u64, modified : u64, encoding : Option<&str>) -> String {
+  match encoding {
+    Some(e) => format!("\"{:x}-{:x}-{}\"", len, modified, e),
+    None => format!("\"{:x}-{:x}\"", len, modified),
+  }
+}
+
+fn etag_matches(headers : &HeaderMap, tag : &str) -> bool {
+  headers.get_all(IF_NONE_MATCH).iter()
+    .filter_map(|v| v.to_str().ok())
+    .flat_map(|v| v.split(','))
+    .map(|t| t.trim().trim_start_matches("W/"))
+    .any(|t| t == "*" || t == tag)
+}
+
+// Parse a single "bytes=start-end" range into an inclusive (start, end).
+// Ok(None) means serve the whole thing, Err means it can't be satisfied.
+fn parse_range(value : &str, len : u64) -> Result<Option<(u64, u64)>, ()> {
+  let spec = match value.trim().strip_prefix("bytes=") {
+    Some(spec) => spec,
+    None => return Ok(None),
+  };
+  // multiple ranges would need a multipart response. Just send it all.
+  if spec.contains(',') {
+    return Ok(None);
+  }
+
+  let mut parts = spec.splitn(2, '-');
+  let start = parts.next().unwrap_or("").trim();
+  let end = parts.next().unwrap_or("").trim();
+
+  let range = if start.is_empty() {
+    // the last n bytes
+    let n : u64 = end.parse().map_err(|_| ())?;
+    if n == 0 { return Err(()); }
+    (len.saturating_sub(n), len.wrapping_sub(1))
+  } else {
+    let start : u64 = start.parse().map_err(|_| ())?;
+    let end = if end.is_empty() { len.wrapping_sub(1) } else { end.parse::<u64>().map_err(|_| ())?.min(len.wrapping_sub(1)) };
+    (start, end)
+  };
+
+  if len == 0 || range.0 > range.1 || range.0 >= len {
+    return Err(());
+  }
+  Ok(Some(range))
+}
+
+async fn open_file(path : &Path) -> Option<(File, u64, u64)> {
+  let file = File::open(path).await.ok()?;
+  let metadata = file.metadata().await.ok()?;
+  if !metadata.is_file() {
+    return None;
+  }
+  let modified = metadata.modified().ok()
+    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+    .map(|d| d.as_secs())
+    .unwrap_or(0);
+  Some((file, metadata.len(), modified))
+}
+
+// send `len` bytes of the file from where it's positioned, a chunk at a time
+fn stream_file(mut file : File, mut len : u64) -> Body {
+  let (mut sender, body) = Body::channel();
+  tokio::spawn(async move {
+    let mut buf = vec![0; CHUNK_SIZE];
+    while len > 0 {
+      let want = (len as usize).min(CHUNK_SIZE);
+      let n = match file.read(&mut buf[..want]).await {
+        Ok(0) | Err(_) => break,
+        Ok(n) => n,
+      };
+      if sender.send_data(Bytes::copy_from_slice(&buf[..n])).await.is_err() {
+        break;
+      }
+      len -= n as u64;
+    }
+  });
+  body
+}
+
+// The first of these with the file wins
+pub async fn serve_first(sets : &[&StaticFiles], req : &Request<Body>) -> io::Result<Option<Response<Body>>> {
+  for files in sets {
+    if let Some(response) = files.serve(req).await? {
+      return Ok(Some(response));
+    }
+  }
+  Ok(None)
+}
+
+impl StaticFiles {
+  // Plain files only
+  pub fn files<P : Into<PathBuf>>(root : P) -> Self {
+    Self { root: root.into(), index: false, spa_fallback: false }
+  }
+
+  // A built site: index.html for directories, and for unknown pages if spa_fallback
+  pub fn site<P : Into<PathBuf>>(root : P, spa_fallback : bool) -> Self {
+    Self { root: root.into(), index: true, spa_fallback }
+  }
+
+  // The response for a GET or HEAD of this path, or None if there's no such file
+  pub async fn serve(&self, req : &Request<Body>) -> io::Result<Option<Response<Body>>> {
+    let request_path = req.uri().path();
+    let mut path = match resolve(&self.root, request_path) {
+      Some(path) => path,
+      None => return Ok(None),
+    };
+    if request_path.ends_with('/') || path == self.root {
+      if !self.index {
+        return Ok(None);
+      }
+      path.push("index.html");
+    }
+
+    if let Some(response) = self.serve_file(req, &path).await? {
+      return Ok(Some(response));
+    }
+
+    // only fall back for things that look like pages, so missing assets still 404
+    let is_page = path.extension().map(|e| e == "html").unwrap_or(true);
+    if self.index && self.spa_fallback && is_page {
+      return self.serve_file(req, &self.root.join("index.html")).await;
+    }
+    Ok(None)
+  }
+
+  async fn serve_file(&self, req : &Request<Body>, path : &Path) -> io::Result<Option<Response<Body>>> {
+    let headers = req.headers();
+    let range = headers.get(RANGE).and_then(|v| v.to_str().ok());
+
+    // ranges refer to the plain file, so only look for compressed versions without one
+    let mut chosen = None;
+    if range.is_none() {
+      for &(encoding, extension) in ENCODINGS.iter() {
+        if !accepts_encoding(headers, encoding) {
+          continue;
+        }
+        let mut name = path.as_os_str().to_owned();
+        name.push(".");
+        name.push(extension);
+        if let Some(found) = open_file(Path::new(&name)).await {
+          chosen = Some((found, Some(encoding)));
+          break;
+        }
+      }
+    }
+
+    let ((mut file, len, modified), encoding) = match chosen {
+      Some(chosen) => chosen,
+      None => match open_file(path).await {
+        Some(found) => (found, None),
+        None => return Ok(None),
+      },
+    };
+
+    let tag = etag(len, modified, encoding);
+    let mut response = Response::builder()
+      .header(ETAG, &tag)
+      .header(VARY, "Accept-Encoding")
+      .header(ACCEPT_RANGES, "bytes")
+      .header(CONTENT_TYPE, content_type(path));
+    if let Some(encoding) = encoding {
+      response = response.header(CONTENT_ENCODING, encoding);
+    }
+
+    if etag_matches(headers, &tag) {
+      return Ok(Some(response.status(StatusCode::NOT_MODIFIED).body(Body::empty()).unwrap()));
+    }
+
+    let (status, start, end) = match range.map(|r| parse_range(r, len)) {
+      Some(Err(())) => {
+        let response = response
+          .status(StatusCode::RANGE_NOT_SATISFIABLE)
+          .header(CONTENT_RANGE, format!("bytes */{}", len))
+          .body(Body::empty())
+          .unwrap();
+        return Ok(Some(response));
+      },
+      Some(Ok(Some((start, end)))) => {
+        response = response.header(CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, len));
+        (StatusCode::PARTIAL_CONTENT, start, end + 1)
+      },
+      _ => (StatusCode::OK, 0, len),
+    };
+
+    let body = if req.method() == Method::HEAD || start == end {
+      Body::empty()
+    } else {
+      file.seek(SeekFrom::Start(start)).await?;
+      stream_file(file, end - start)
+    };
+
+    let response = response
+      .status(status)
+      .header(CONTENT_LENGTH, end - start)
+      .body(body)
+      .unwrap();
+    Ok(Some(response))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use hyper::header::HeaderValue;
+
+  #[test]
+  fn test_resolve_stays_in_root() {
+    let root = Path::new("/srv/data");
+    assert_eq!(resolve(root, "/block_data_state_37.json"), Some(root.join("block_data_state_37.json")));
+    assert_eq!(resolve(root, "/js/./app.js"), Some(root.join("js/app.js")));
+    assert_eq!(resolve(root, "/../etc/passwd"), None);
+    assert_eq!(resolve(root, "/js/../../etc/passwd"), None);
+  }
+
+  #[test]
+  fn test_parse_range() {
+    assert_eq!(parse_range("bytes=0-99", 1000), Ok(Some((0, 99))));
+    assert_eq!(parse_range("bytes=900-", 1000), Ok(Some((900, 999))));
+    assert_eq!(parse_range("bytes=-100", 1000), Ok(Some((900, 999))));
+    assert_eq!(parse_range("bytes=500-5000", 1000), Ok(Some((500, 999))));
+    assert_eq!(parse_range("bytes=0-1,5-6", 1000), Ok(None));
+    assert_eq!(parse_range("bytes=1000-", 1000), Err(()));
+    assert_eq!(parse_range("bytes=5-2", 1000), Err(()));
+    assert_eq!(parse_range("bytes=0-", 0), Err(()));
+  }
+
+  #[test]
+  fn test_accepts_encoding() {
+    let mut headers = HeaderMap::new();
+    headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip, deflate, br;q=0"));
+    assert!(accepts_encoding(&headers, "gzip"));
+    assert!(!accepts_encoding(&headers, "br"));
+    assert!(!accepts_encoding(&HeaderMap::new(), "gzip"));
+  }
+
+  #[tokio::test]
+  async fn test_index_comes_from_the_site() {
+    let dir = std::env::temp_dir().join(format!("redistrict_files_{}", std::process::id()));
+    let (data_dir, dist_dir) = (dir.join("public"), dir.join("dist"));
+    std::fs::create_dir_all(&data_dir).unwrap();
+    std::fs::create_dir_all(&dist_dir).unwrap();
+    std::fs::write(data_dir.join("index.html"), "template").unwrap();
+    std::fs::write(data_dir.join("block_data_state_37.json"), "{}").unwrap();
+    std::fs::write(dist_dir.join("index.html"), "built").unwrap();
+
+    let data = StaticFiles::files(&data_dir);
+    let site = StaticFiles::site(&dist_dir, true);
+    let get = |path : &str| Request::get(path).body(Body::empty()).unwrap();
+    let body = |response : Option<Response<Body>>| async {
+      let bytes = hyper::body::to_bytes(response.unwrap().into_body()).await.unwrap();
+      String::from_utf8(bytes.to_vec()).unwrap()
+    };
+
+    assert_eq!(body(serve_first(&[&data, &site], &get("/")).await.unwrap()).await, "built");
+    assert_eq!(body(serve_first(&[&data, &site], &get("/plans/abc")).await.unwrap()).await, "built");
+    assert_eq!(body(serve_first(&[&data, &site], &get("/block_data_state_37.json")).await.unwrap()).await, "{}");
+    assert!(data.serve(&get("/")).await.unwrap().is_none());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn test_etag_matches() {
+    let mut headers = HeaderMap::new();
+    let tag = etag(10, 20, Some("br"));
+    headers.insert(IF_NONE_MATCH, HeaderValue::from_str(&format!("\"x\", W/{}", tag)).unwrap());
+    assert!(etag_matches(&headers, &tag));
+    assert!(!etag_matches(&headers, &etag(10, 20, None)));
+  }
+}
@@ -0,0 +1,581 @@
+// Background solves.
+//
+// Jobs wait in the queue until one of a fixed number of worker slots is free,
+// then solve on the blocking thread pool. Progress is recorded after every
+// iteration, and a cancelled job stops at the end of its current iteration.
+// Finished jobs are kept around for a while so clients can come back for
+// the result, then pruned. The plans themselves are saved in the plan store.
+// Clients that want to watch a job can subscribe to its events instead of
+// polling the status.
+//
+// On shutdown, running jobs get a while to finish. Any that don't (and any
+// still queued) are written out as checkpoints and picked up again on the
+// next start.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use serde::{Serialize, Deserialize};
+use tokio::sync::{broadcast, Semaphore};
+use log::{info, warn};
+
+use crate::AppState;
+use crate::plans::{Plan, PlanRequest};
+use redistrict_core::solver::{BlockEntry, Center, Solver, StepReport};
+
+// how many events a slow subscriber can fall behind by before it misses some
+const EVENT_CAPACITY : usize = 64;
+// how often to look in on running jobs while shutting down
+const SHUTDOWN_POLL : Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone)]
+pub struct JobConfig {
+  // how many jobs may solve at once
+  pub workers: usize,
+  // how many jobs may be waiting for a worker before we turn new ones away
+  pub max_queued: usize,
+  // how long finished jobs are kept
+  pub retention: Duration,
+}
+
+impl Default for JobConfig {
+  fn default() -> Self {
+    Self {
+      workers: 2,
+      max_queued: 16,
+      retention: Duration::from_secs(60 * 60),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+  Queued,
+  Running,
+  Done,
+  Failed,
+  Cancelled,
+}
+
+impl JobState {
+  pub fn is_finished(self) -> bool {
+    !matches!(self, JobState::Queued | JobState::Running)
+  }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JobStatus {
+  pub id: String,
+  pub state: JobState,
+  pub state_code: u32,
+  pub num_districts: usize,
+  // the latest iteration, once the job is running
+  pub progress: Option<StepReport>,
+  pub plan_id: Option<String>,
+  pub error: Option<String>,
+}
+
+// Where the solve is at after an iteration, for drawing it as it goes
+#[derive(Debug, Clone, Serialize)]
+pub struct StepEvent {
+  #[serde(flatten)]
+  pub step: StepReport,
+  pub centers: Vec<Center>,
+  pub populations: Vec<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub enum JobEvent {
+  Step(Arc<StepEvent>),
+  // the final status, and the plan if the job is done
+  Finished(JobStatus, Option<Arc<Plan>>),
+}
+
+// Enough to carry on with a job after a restart
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Checkpoint {
+  // the job keeps its id, so clients can carry on polling it. Missing from
+  // checkpoints older than that.
+  #[serde(default)]
+  pub id: Option<String>,
+  pub request: PlanRequest,
+  pub iteration: usize,
+  // empty if the job never started
+  pub centers: Vec<Center>,
+}
+
+pub struct Job {
+  pub request: PlanRequest,
+  status: Mutex<JobStatus>,
+  cancelled: AtomicBool,
+  // cancelled by a shutdown rather than a client, so it should be checkpointed
+  interrupted: AtomicBool,
+  finished_at: Mutex<Option<Instant>>,
+  events: broadcast::Sender<JobEvent>,
+  // where to start from, if resuming a checkpoint
+  resume: Option<Checkpoint>,
+  latest: Mutex<Option<Arc<StepEvent>>>,
+}
+
+impl Job {
+  fn new(id : String, request : PlanRequest, resume : Option<Checkpoint>) -> Self {
+    let status = JobStatus {
+      id,
+      state: JobState::Queued,
+      state_code: request.state_code,
+      num_districts: request.config.num_districts,
+      progress: None,
+      plan_id: None,
+      error: None,
+    };
+
+    Self {
+      request,
+      status: Mutex::new(status),
+      cancelled: AtomicBool::new(false),
+      interrupted: AtomicBool::new(false),
+      finished_at: Mutex::new(None),
+      events: broadcast::channel(EVENT_CAPACITY).0,
+      resume,
+      latest: Mutex::new(None),
+    }
+  }
+
+  // Events from now until the job finishes. Check the status after
+  // subscribing, in case it finished already.
+  pub fn subscribe(&self) -> broadcast::Receiver<JobEvent> {
+    self.events.subscribe()
+  }
+
+  pub fn status(&self) -> JobStatus {
+    self.status.lock().unwrap().clone()
+  }
+
+  // Wait for the job to finish, and give its final status
+  pub async fn finished(&self) -> JobStatus {
+    let mut events = self.subscribe();
+    loop {
+      let status = self.status();
+      if status.state.is_finished() {
+        return status;
+      }
+      match events.recv().await {
+        Ok(JobEvent::Finished(status, _)) => return status,
+        Ok(JobEvent::Step(_)) | Err(broadcast::RecvError::Lagged(_)) => {},
+        Err(broadcast::RecvError::Closed) => return self.status(),
+      }
+    }
+  }
+
+  pub fn is_cancelled(&self) -> bool {
+    self.cancelled.load(Ordering::SeqCst)
+  }
+
+  fn set_progress<B : AsRef<[BlockEntry]>>(&self, solver : &Solver<B>, step : &StepReport) {
+    self.status.lock().unwrap().progress = Some(step.clone());
+
+    let event = Arc::new(StepEvent {
+      step: step.clone(),
+      centers: solver.centers().to_vec(),
+      populations: solver.populations().to_vec(),
+    });
+    *self.latest.lock().unwrap() = Some(event.clone());
+    // nobody listening is fine
+    let _ = self.events.send(JobEvent::Step(event));
+  }
+
+  // where the job has got to, so it can be carried on later
+  pub fn checkpoint(&self) -> Checkpoint {
+    let id = Some(self.status().id);
+    match &*self.latest.lock().unwrap() {
+      Some(event) => Checkpoint {
+        id,
+        request: self.request.clone(),
+        iteration: event.step.iteration,
+        centers: event.centers.clone(),
+      },
+      None => match &self.resume {
+        Some(resume) => Checkpoint { id, ..resume.clone() },
+        None => Checkpoint { id, request: self.request.clone(), iteration: 0, centers: vec![] },
+      },
+    }
+  }
+
+  // Claim a queued job for a worker. Like cancel, the check and the change
+  // happen under one lock, so only one of them wins.
+  fn start(&self) -> bool {
+    let mut status = self.status.lock().unwrap();
+    if status.state != JobState::Queued {
+      return false;
+    }
+    status.state = JobState::Running;
+    true
+  }
+
+  fn finish(&self, state : JobState, plan : Option<Arc<Plan>>, error : Option<String>) {
+    let mut status = self.status.lock().unwrap();
+    self.finish_locked(&mut status, state, plan, error);
+  }
+
+  fn finish_locked(&self, status : &mut JobStatus, state : JobState, plan : Option<Arc<Plan>>, error : Option<String>) {
+    if status.state.is_finished() {
+      return;
+    }
+    status.state = state;
+    status.plan_id = plan.as_ref().map(|p| p.id.clone());
+    status.error = error;
+    *self.finished_at.lock().unwrap() = Some(Instant::now());
+
+    let _ = self.events.send(JobEvent::Finished(status.clone(), plan));
+  }
+
+  // Ask the job to stop. Queued jobs are cancelled straight away, running
+  // ones once their current iteration is done.
+  pub fn cancel(&self) {
+    self.cancelled.store(true, Ordering::SeqCst);
+    let mut status = self.status.lock().unwrap();
+    if status.state == JobState::Queued {
+      self.finish_locked(&mut status, JobState::Cancelled, None, None);
+    }
+  }
+
+  fn expired(&self, retention : Duration, now : Instant) -> bool {
+    match *self.finished_at.lock().unwrap() {
+      Some(t) => now.duration_since(t) >= retention,
+      None => false,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SubmitError {
+  QueueFull,
+  ShuttingDown,
+}
+
+pub struct JobQueue {
+  config: JobConfig,
+  jobs: Mutex<HashMap<String, Arc<Job>>>,
+  workers: Arc<Semaphore>,
+  closed: AtomicBool,
+}
+
+// Solve a job on the current thread. Returns None if it was cancelled part way.
+fn run(state : &AppState, job : &Job) -> io::Result<Option<Plan>> {
+  let data = state.blocks.get(job.request.state_code)?;
+  let config = job.request.config.clone();
+  let mut solver = match &job.resume {
+    Some(checkpoint) => Solver::resume(&data.blocks, config, checkpoint.centers.clone(), checkpoint.iteration),
+    None => Solver::new(&data.blocks, config),
+  };
+
+  let finished = solver.solve_with(|solver, step| {
+    job.set_progress(solver, step);
+    !job.is_cancelled()
+  });
+
+  if !finished {
+    return Ok(None);
+  }
+  let plan = Plan::new(&job.request, &data, &solver);
+  state.metrics.observe_iterations(job.request.config.algorithm, plan.report.iterations);
+  Ok(Some(plan))
+}
+
+impl JobQueue {
+  pub fn new(config : JobConfig) -> Self {
+    Self {
+      workers: Arc::new(Semaphore::new(config.workers.max(1))),
+      config,
+      jobs: Mutex::new(HashMap::new()),
+      closed: AtomicBool::new(false),
+    }
+  }
+
+  pub fn get(&self, id : &str) -> Option<Arc<Job>> {
+    self.jobs.lock().unwrap().get(id).cloned()
+  }
+
+  pub fn remove(&self, id : &str) -> Option<Arc<Job>> {
+    self.jobs.lock().unwrap().remove(id)
+  }
+
+  // jobs still waiting for a worker
+  pub fn queued(&self) -> usize {
+    self.jobs.lock().unwrap().values().filter(|j| j.status().state == JobState::Queued).count()
+  }
+
+  // Forget finished jobs older than the retention period
+  pub fn prune(&self) {
+    let now = Instant::now();
+    let retention = self.config.retention;
+    self.jobs.lock().unwrap().retain(|_, job| !job.expired(retention, now));
+  }
+
+  pub fn submit(&self, state : Arc<AppState>, request : PlanRequest) -> Result<Arc<Job>, SubmitError> {
+    if self.closed.load(Ordering::SeqCst) {
+      return Err(SubmitError::ShuttingDown);
+    }
+    self.prune();
+    if self.queued() >= self.config.max_queued {
+      return Err(SubmitError::QueueFull);
+    }
+
+    Ok(self.enqueue(state, request, None, None))
+  }
+
+  // Random, rather than counting up, so ids from before a restart are never
+  // given to different jobs after it
+  fn new_id(jobs : &HashMap<String, Arc<Job>>) -> String {
+    loop {
+      let id = format!("{:016x}", rand::random::<u64>());
+      if !jobs.contains_key(&id) {
+        return id;
+      }
+    }
+  }
+
+  // id is the job's id from before a restart, if it has one
+  fn enqueue(&self, state : Arc<AppState>, request : PlanRequest, resume : Option<Checkpoint>, id : Option<String>) -> Arc<Job> {
+    let job = {
+      let mut jobs = self.jobs.lock().unwrap();
+      let id = match id {
+        Some(id) if !jobs.contains_key(&id) => id,
+        _ => Self::new_id(&jobs),
+      };
+      let job = Arc::new(Job::new(id.clone(), request, resume));
+      jobs.insert(id, job.clone());
+      job
+    };
+
+    let workers = self.workers.clone();
+    let task_job = job.clone();
+    tokio::spawn(async move {
+      let _permit = workers.acquire().await;
+      // it may have been cancelled while it waited
+      if !task_job.start() {
+        return;
+      }
+      info!("Job {} started", task_job.status().id);
+      let started = Instant::now();
+
+      let solve_job = task_job.clone();
+      let metrics_state = state.clone();
+      let result = tokio::task::spawn_blocking(move || run(&state, &solve_job).and_then(|plan| {
+        plan.map(|plan| state.plans.insert(plan)).transpose()
+      })).await;
+
+      match result {
+        Ok(Ok(Some(plan))) => task_job.finish(JobState::Done, Some(plan), None),
+        Ok(Ok(None)) => task_job.finish(JobState::Cancelled, None, None),
+        Ok(Err(e)) => task_job.finish(JobState::Failed, None, Some(e.to_string())),
+        Err(e) => task_job.finish(JobState::Failed, None, Some(e.to_string())),
+      }
+      let status = task_job.status();
+      info!("Job {} {:?}", status.id, status.state);
+      metrics_state.metrics.observe_job(task_job.request.config.algorithm, status.state, started.elapsed());
+    });
+
+    job
+  }
+
+  pub fn running(&self) -> Vec<Arc<Job>> {
+    self.jobs.lock().unwrap().values()
+      .filter(|j| j.status().state == JobState::Running)
+      .cloned()
+      .collect()
+  }
+
+  // Stop taking jobs, give the running ones until the timeout to finish, and
+  // checkpoint whatever is left into the directory. Returns how many were checkpointed.
+  pub async fn shutdown(&self, timeout : Duration, checkpoint_dir : &Path) -> io::Result<usize> {
+    self.closed.store(true, Ordering::SeqCst);
+    let unfinished : Vec<Arc<Job>> = self.jobs.lock().unwrap().values()
+      .filter(|j| !j.status().state.is_finished())
+      .cloned()
+      .collect();
+
+    // queued jobs won't get a worker now
+    for job in unfinished.iter().filter(|j| j.status().state == JobState::Queued) {
+      job.interrupted.store(true, Ordering::SeqCst);
+      job.cancel();
+    }
+
+    let deadline = Instant::now() + timeout;
+    while !self.running().is_empty() && Instant::now() < deadline {
+      tokio::time::delay_for(SHUTDOWN_POLL).await;
+    }
+
+    // the rest stop at the end of their current iteration
+    for job in self.running() {
+      job.interrupted.store(true, Ordering::SeqCst);
+      job.cancel();
+    }
+    while !self.running().is_empty() {
+      tokio::time::delay_for(SHUTDOWN_POLL).await;
+    }
+
+    let interrupted : Vec<&Arc<Job>> = unfinished.iter()
+      .filter(|j| j.interrupted.load(Ordering::SeqCst) && j.status().state == JobState::Cancelled)
+      .collect();
+    if !interrupted.is_empty() {
+      fs::create_dir_all(checkpoint_dir)?;
+    }
+    for job in interrupted.iter() {
+      let path = checkpoint_dir.join(format!("job_{}.json", job.status().id));
+      fs::write(path, serde_json::to_vec(&job.checkpoint())?)?;
+    }
+    Ok(interrupted.len())
+  }
+
+  // Queue up the jobs checkpointed by the last shutdown. Returns how many there were.
+  pub fn resume_checkpoints(&self, state : Arc<AppState>, checkpoint_dir : &Path) -> io::Result<usize> {
+    let entries = match fs::read_dir(checkpoint_dir) {
+      Ok(entries) => entries,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+      Err(e) => return Err(e),
+    };
+
+    let mut count = 0;
+    for entry in entries {
+      let path = entry?.path();
+      if path.extension().map(|e| e != "json").unwrap_or(true) {
+        continue;
+      }
+
+      let checkpoint : Checkpoint = match fs::read(&path).map(|bytes| serde_json::from_slice(&bytes)) {
+        Ok(Ok(checkpoint)) => checkpoint,
+        Ok(Err(e)) => {
+          warn!("Skipping bad checkpoint {}: {}", path.display(), e);
+          continue;
+        },
+        Err(e) => return Err(e),
+      };
+
+      let resume = if checkpoint.centers.is_empty() { None } else { Some(checkpoint.clone()) };
+      let job = self.enqueue(state.clone(), checkpoint.request, resume, checkpoint.id);
+      info!("Resumed job {}", job.status().id);
+      fs::remove_file(&path)?;
+      count += 1;
+    }
+    Ok(count)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn request() -> PlanRequest {
+    serde_json::from_value(serde_json::json!({
+      "state_code": 37,
+      "num_districts": 3,
+    })).unwrap()
+  }
+
+  #[test]
+  fn test_cancel_queued_job() {
+    let job = Job::new("1".into(), request(), None);
+    job.cancel();
+    assert_eq!(job.status().state, JobState::Cancelled);
+    assert!(job.is_cancelled());
+    // a worker picking it up afterwards leaves it alone
+    assert!(!job.start());
+    assert!(job.expired(Duration::from_secs(0), Instant::now()));
+  }
+
+  #[test]
+  fn test_cancel_running_job() {
+    let job = Job::new("1".into(), request(), None);
+    assert!(job.start());
+    job.cancel();
+    // it's still solving, so it stays running until the worker sees the flag
+    assert_eq!(job.status().state, JobState::Running);
+    assert!(job.is_cancelled());
+    assert!(!job.expired(Duration::from_secs(0), Instant::now()));
+  }
+
+  #[test]
+  fn test_subscribers_see_finish() {
+    let job = Job::new("1".into(), request(), None);
+    let mut events = job.subscribe();
+    job.cancel();
+    match events.try_recv() {
+      Ok(JobEvent::Finished(status, None)) => assert_eq!(status.state, JobState::Cancelled),
+      other => panic!("unexpected event {:?}", other),
+    }
+  }
+
+  #[test]
+  fn test_checkpoint_before_start() {
+    let job = Job::new("1".into(), request(), None);
+    let checkpoint = job.checkpoint();
+    assert_eq!(checkpoint.id.as_deref(), Some("1"));
+    assert_eq!(checkpoint.iteration, 0);
+    assert!(checkpoint.centers.is_empty());
+    assert_eq!(checkpoint.request.config.num_districts, 3);
+  }
+
+  #[test]
+  fn test_checkpoints_keep_the_job_id() {
+    let job = Job::new("abc".into(), request(), None);
+    let json = serde_json::to_value(job.checkpoint()).unwrap();
+    let checkpoint : Checkpoint = serde_json::from_value(json).unwrap();
+    assert_eq!(checkpoint.id.as_deref(), Some("abc"));
+
+    // checkpointed again after resuming, it's still the same job
+    let resumed = Job::new("abc".into(), request(), Some(checkpoint));
+    assert_eq!(resumed.checkpoint().id.as_deref(), Some("abc"));
+
+    // older checkpoints have no id
+    let old : Checkpoint = serde_json::from_value(serde_json::json!({
+      "request": { "state_code": 37, "num_districts": 3 },
+      "iteration": 0,
+      "centers": [],
+    })).unwrap();
+    assert_eq!(old.id, None);
+  }
+
+  #[test]
+  fn test_new_ids_are_unique() {
+    let mut jobs = HashMap::new();
+    for _ in 0..100 {
+      let id = JobQueue::new_id(&jobs);
+      assert!(!jobs.contains_key(&id));
+      jobs.insert(id.clone(), Arc::new(Job::new(id, request(), None)));
+    }
+  }
+
+  #[tokio::test]
+  async fn test_wait_for_finish() {
+    let job = Arc::new(Job::new("1".into(), request(), None));
+    let waiting = tokio::spawn({
+      let job = job.clone();
+      async move { job.finished().await }
+    });
+    assert!(job.start());
+    job.finish(JobState::Failed, None, Some("no data".into()));
+    let status = waiting.await.unwrap();
+    assert_eq!(status.state, JobState::Failed);
+    // and straight away once it has
+    assert_eq!(job.finished().await.state, JobState::Failed);
+  }
+
+  #[test]
+  fn test_finished_state_sticks() {
+    let job = Job::new("1".into(), request(), None);
+    assert!(job.start());
+    job.finish(JobState::Failed, None, Some("no data".into()));
+    job.cancel();
+
+    let status = job.status();
+    assert_eq!(status.state, JobState::Failed);
+    assert_eq!(status.error, Some("no data".into()));
+    assert!(!job.expired(Duration::from_secs(60), Instant::now()));
+  }
+}
@@ -1,43 +1,541 @@
+use std::collections::HashMap;
 use std::convert::Infallible;
-use std::net::SocketAddr;
+use std::env;
+use std::sync::Arc;
+use std::time::Instant;
 use hyper::{Body, Request, Response, Server, Method, StatusCode};
+use hyper::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
 use hyper::service::{make_service_fn, service_fn};
+use serde::Serialize;
+use log::{info, warn, error};
 
-async fn hello_world(req: Request<Body>) -> Result<Response<Body>, Infallible> {
-  let mut response = Response::new(Body::empty());
+mod blocks;
+mod plans;
+mod jobs;
+mod events;
+mod files;
+mod config;
+mod boundaries;
+mod exports;
+mod metrics;
+use redistrict_core::{compare, imports};
+use redistrict_core::imports::ImportFormat;
+use blocks::BlockCache;
+use plans::{Plan, PlanFilter, PlanRequest, PlanStore};
+use jobs::{JobQueue, JobState, SubmitError};
+use files::StaticFiles;
+use config::Config;
+use exports::ExportFormat;
+use metrics::Metrics;
 
-  match (req.method(), req.uri().path()) {
-    (&Method::GET, "/") => {
-      *response.body_mut() = Body::from("Try POSTing data to /echo");
+pub struct AppState {
+  blocks: BlockCache,
+  plans: PlanStore,
+  jobs: JobQueue,
+  data: StaticFiles,
+  frontend: StaticFiles,
+  metrics: Metrics,
+}
+
+fn json_response<T : Serialize>(status : StatusCode, value : &T) -> Response<Body> {
+  match serde_json::to_vec(value) {
+    Ok(body) => Response::builder()
+      .status(status)
+      .header(CONTENT_TYPE, "application/json")
+      .body(Body::from(body))
+      .unwrap(),
+    Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+  }
+}
+
+fn error_response(status : StatusCode, message : &str) -> Response<Body> {
+  let body = serde_json::json!({ "error": message }).to_string();
+  Response::builder()
+    .status(status)
+    .header(CONTENT_TYPE, "application/json")
+    .body(Body::from(body))
+    .unwrap()
+}
+
+async fn read_plan_request(req : Request<Body>) -> Result<PlanRequest, Response<Body>> {
+  let body = hyper::body::to_bytes(req.into_body()).await
+    .map_err(|e| error_response(StatusCode::BAD_REQUEST, &e.to_string()))?;
+
+  let request : PlanRequest = serde_json::from_slice(&body)
+    .map_err(|e| error_response(StatusCode::BAD_REQUEST, &e.to_string()))?;
+
+  if request.config.num_districts == 0 {
+    return Err(error_response(StatusCode::BAD_REQUEST, "num_districts must be at least 1"));
+  }
+  Ok(request)
+}
+
+async fn create_plan(state : Arc<AppState>, req : Request<Body>) -> Response<Body> {
+  let request = match read_plan_request(req).await {
+    Ok(request) => request,
+    Err(response) => return response,
+  };
+
+  if !blocks::block_data_path(state.blocks.data_dir(), request.state_code).exists() {
+    return error_response(StatusCode::NOT_FOUND, "No block data for that state");
+  }
+
+  // it's a job like any other, so it waits its turn for a worker. This just
+  // waits for it.
+  let job = match state.jobs.submit(state.clone(), request) {
+    Ok(job) => job,
+    Err(SubmitError::QueueFull) => return error_response(StatusCode::SERVICE_UNAVAILABLE, "Too many jobs queued, try again later"),
+    Err(SubmitError::ShuttingDown) => return error_response(StatusCode::SERVICE_UNAVAILABLE, "The server is shutting down"),
+  };
+
+  let status = job.finished().await;
+  match (status.state, status.plan_id) {
+    (JobState::Done, Some(id)) => json_response(StatusCode::CREATED, &serde_json::json!({ "id": id })),
+    (JobState::Cancelled, _) => error_response(StatusCode::SERVICE_UNAVAILABLE, "The solve was cancelled"),
+    (_, _) => error_response(
+      StatusCode::INTERNAL_SERVER_ERROR,
+      &status.error.unwrap_or_else(|| "The solve failed".to_string())
+    ),
+  }
+}
+
+// POST /plans/import?state_code=37&format=baf|geojson with the file as the body
+async fn import_plan(state : Arc<AppState>, req : Request<Body>) -> Response<Body> {
+  let params = query_params(req.uri().query());
+  let state_code : u32 = match params.get("state_code").map(|c| c.parse()) {
+    Some(Ok(code)) => code,
+    _ => return error_response(StatusCode::BAD_REQUEST, "state_code is required"),
+  };
+  let format = match ImportFormat::from_name(params.get("format").map(String::as_str).unwrap_or("baf")) {
+    Some(format) => format,
+    None => return error_response(StatusCode::BAD_REQUEST, "format should be baf or geojson"),
+  };
+
+  let body = match hyper::body::to_bytes(req.into_body()).await {
+    Ok(body) => body,
+    Err(e) => return error_response(StatusCode::BAD_REQUEST, &e.to_string()),
+  };
+
+  // matching a state's worth of blocks takes a moment
+  let import_state = state.clone();
+  let result = tokio::task::spawn_blocking(move || {
+    let data = import_state.blocks.get(state_code)?;
+    let import = match format {
+      ImportFormat::Baf => {
+        let text = std::str::from_utf8(&body)
+          .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        let geoids = exports::load_geoids(import_state.blocks.data_dir(), state_code)?;
+        if geoids.len() != data.blocks.len() {
+          return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "The block GEOIDs don't match the block data"));
+        }
+        imports::import_baf(text, &geoids)?
+      },
+      ImportFormat::GeoJson => imports::import_geojson(&body, &data.blocks)?,
+    };
+
+    let unmatched = import.unmatched;
+    if unmatched > 0 {
+      info!("{} blocks were outside the imported districts and went to the nearest one", unmatched);
+    }
+    let plan = import_state.plans.insert(Plan::imported(state_code, &data, format, import)?)?;
+    Ok((plan, unmatched))
+  }).await;
+
+  match result {
+    Ok(Ok((plan, unmatched))) => {
+      json_response(StatusCode::CREATED, &serde_json::json!({ "id": plan.id, "unmatched_blocks": unmatched }))
     },
-    (&Method::GET, "/echo") => {
-      *response.body_mut() = Body::from(req.uri().query().map(|c| c.to_string()).unwrap_or_default());
+    Ok(Err(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+      error_response(StatusCode::NOT_FOUND, "No block data or block GEOIDs for that state")
     },
-    _ => {
-      *response.status_mut() = StatusCode::NOT_FOUND;
-      *response.body_mut() = Body::from("<p>Not Found</p>");
+    Ok(Err(e)) if e.kind() == std::io::ErrorKind::InvalidData => error_response(StatusCode::BAD_REQUEST, &e.to_string()),
+    Ok(Err(e)) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+  }
+}
+
+fn get_plan(state : Arc<AppState>, id : &str) -> Response<Body> {
+  match state.plans.get(id) {
+    Ok(Some(plan)) => json_response(StatusCode::OK, &*plan),
+    Ok(None) => error_response(StatusCode::NOT_FOUND, "No such plan"),
+    Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+  }
+}
+
+fn delete_plan(state : Arc<AppState>, id : &str) -> Response<Body> {
+  match state.plans.remove(id) {
+    Ok(true) => Response::builder().status(StatusCode::NO_CONTENT).body(Body::empty()).unwrap(),
+    Ok(false) => error_response(StatusCode::NOT_FOUND, "No such plan"),
+    Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+  }
+}
+
+// GET /plans/{id}/export?format=baf|geojson|shapefile
+async fn export_plan(state : Arc<AppState>, id : &str, req : &Request<Body>) -> Response<Body> {
+  let params = query_params(req.uri().query());
+  let format = match ExportFormat::from_name(params.get("format").map(String::as_str).unwrap_or("baf")) {
+    Some(format) => format,
+    None => return error_response(StatusCode::BAD_REQUEST, "format should be baf, geojson or shapefile"),
+  };
+
+  let plan = match state.plans.get(id) {
+    Ok(Some(plan)) => plan,
+    Ok(None) => return error_response(StatusCode::NOT_FOUND, "No such plan"),
+    Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+  };
+
+  // dissolving a state's worth of blocks takes a moment
+  let export_state = state.clone();
+  let export_plan = plan.clone();
+  let result = tokio::task::spawn_blocking(move || {
+    exports::export(export_state.blocks.data_dir(), &export_plan, format)
+  }).await;
+
+  match result {
+    Ok(Ok(bytes)) => Response::builder()
+      .status(StatusCode::OK)
+      .header(CONTENT_TYPE, format.content_type())
+      .header(CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", format.file_name(&plan)))
+      .body(Body::from(bytes))
+      .unwrap(),
+    Ok(Err(e)) if e.kind() == std::io::ErrorKind::NotFound => error_response(StatusCode::NOT_FOUND, &e.to_string()),
+    Ok(Err(e)) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+  }
+}
+
+// GET /plans/{id}/compare?with={other id}
+async fn compare_plans(state : Arc<AppState>, id : &str, req : &Request<Body>) -> Response<Body> {
+  let params = query_params(req.uri().query());
+  let other_id = match params.get("with") {
+    Some(other) => other.to_string(),
+    None => return error_response(StatusCode::BAD_REQUEST, "with should be the id of a plan to compare against"),
+  };
+
+  let mut plans = vec![];
+  for id in &[id, other_id.as_str()] {
+    match state.plans.get(id) {
+      Ok(Some(plan)) => plans.push(plan),
+      Ok(None) => return error_response(StatusCode::NOT_FOUND, &format!("No such plan {}", id)),
+      Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    }
+  }
+  let (a, b) = (plans[0].clone(), plans[1].clone());
+  if a.state_code != b.state_code {
+    return error_response(StatusCode::BAD_REQUEST, "The plans are of different states");
+  }
+
+  let compare_state = state.clone();
+  let result = tokio::task::spawn_blocking(move || {
+    let data = compare_state.blocks.get(a.state_code)?;
+    compare::compare(&data.blocks, &a.assignment, a.config.num_districts, &b.assignment, b.config.num_districts)
+      .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))
+  }).await;
+
+  match result {
+    Ok(Ok(comparison)) => json_response(StatusCode::OK, &comparison),
+    Ok(Err(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+      error_response(StatusCode::NOT_FOUND, "No block data for that state")
     },
+    Ok(Err(e)) if e.kind() == std::io::ErrorKind::InvalidData => error_response(StatusCode::BAD_REQUEST, &e.to_string()),
+    Ok(Err(e)) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+  }
+}
+
+// percent (and + for space) decoded, as browsers encode them
+fn query_params(query : Option<&str>) -> HashMap<String, String> {
+  form_urlencoded::parse(query.unwrap_or("").as_bytes()).into_owned().collect()
+}
+
+// GET /plans?state_code=37&num_districts=14&algorithm=power&imported=false
+fn list_plans(state : Arc<AppState>, req : &Request<Body>) -> Response<Body> {
+  let params = query_params(req.uri().query());
+  let mut filter = PlanFilter::default();
+  let parsed = (|| -> Result<(), String> {
+    if let Some(code) = params.get("state_code") {
+      filter.state_code = Some(code.parse().map_err(|_| "Invalid state_code")?);
+    }
+    if let Some(k) = params.get("num_districts") {
+      filter.num_districts = Some(k.parse().map_err(|_| "Invalid num_districts")?);
+    }
+    if let Some(algorithm) = params.get("algorithm") {
+      let value = serde_json::Value::String(algorithm.to_string());
+      filter.algorithm = Some(serde_json::from_value(value).map_err(|_| "Invalid algorithm")?);
+    }
+    if let Some(imported) = params.get("imported") {
+      filter.imported = Some(imported.parse().map_err(|_| "Invalid imported, should be true or false")?);
+    }
+    Ok(())
+  })();
+
+  match parsed {
+    Ok(()) => json_response(StatusCode::OK, &state.plans.list(&filter)),
+    Err(e) => error_response(StatusCode::BAD_REQUEST, &e),
+  }
+}
+
+// Queue a solve and hand back the job id straight away
+async fn create_job(state : Arc<AppState>, req : Request<Body>) -> Response<Body> {
+  let request = match read_plan_request(req).await {
+    Ok(request) => request,
+    Err(response) => return response,
+  };
+
+  match state.jobs.submit(state.clone(), request) {
+    Ok(job) => json_response(StatusCode::ACCEPTED, &job.status()),
+    Err(SubmitError::QueueFull) => error_response(StatusCode::SERVICE_UNAVAILABLE, "Too many jobs queued, try again later"),
+    Err(SubmitError::ShuttingDown) => error_response(StatusCode::SERVICE_UNAVAILABLE, "The server is shutting down"),
+  }
+}
+
+fn get_job(state : Arc<AppState>, id : &str) -> Response<Body> {
+  state.jobs.prune();
+  match state.jobs.get(id) {
+    Some(job) => json_response(StatusCode::OK, &job.status()),
+    None => error_response(StatusCode::NOT_FOUND, "No such job"),
+  }
+}
+
+fn watch_job(state : Arc<AppState>, id : &str) -> Response<Body> {
+  match state.jobs.get(id) {
+    Some(job) => events::job_events(state, job),
+    None => error_response(StatusCode::NOT_FOUND, "No such job"),
+  }
+}
+
+// Cancels a queued or running job. Finished jobs are forgotten instead.
+fn delete_job(state : Arc<AppState>, id : &str) -> Response<Body> {
+  let job = match state.jobs.get(id) {
+    Some(job) => job,
+    None => return error_response(StatusCode::NOT_FOUND, "No such job"),
   };
 
+  if job.status().state.is_finished() {
+    state.jobs.remove(id);
+  } else {
+    job.cancel();
+  }
+  json_response(StatusCode::OK, &job.status())
+}
+
+fn not_found() -> Response<Body> {
+  let mut response = Response::new(Body::from("<p>Not Found</p>"));
+  *response.status_mut() = StatusCode::NOT_FOUND;
+  response
+}
+
+// Block data first, then the frontend (which falls back to index.html)
+async fn serve_static(state : Arc<AppState>, req : Request<Body>) -> Response<Body> {
+  match files::serve_first(&[&state.data, &state.frontend], &req).await {
+    Ok(Some(response)) => response,
+    Ok(None) => not_found(),
+    Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+  }
+}
+
+// Alive, as long as we can answer
+fn healthz() -> Response<Body> {
+  json_response(StatusCode::OK, &serde_json::json!({ "status": "ok" }))
+}
+
+// Ready to serve plans, which needs the block data
+fn readyz(state : Arc<AppState>) -> Response<Body> {
+  let data_dir = state.blocks.data_dir();
+  match std::fs::read_dir(data_dir) {
+    Ok(_) => json_response(StatusCode::OK, &serde_json::json!({ "status": "ready" })),
+    Err(e) => error_response(
+      StatusCode::SERVICE_UNAVAILABLE,
+      &format!("Can't read the data directory {}: {}", data_dir.display(), e)
+    ),
+  }
+}
+
+fn metrics(state : Arc<AppState>) -> Response<Body> {
+  let text = state.metrics.render(state.jobs.queued(), state.jobs.running().len());
+  Response::builder()
+    .status(StatusCode::OK)
+    .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+    .body(Body::from(text))
+    .unwrap()
+}
+
+// Handle the request, counting and timing it
+async fn route(state : Arc<AppState>, req : Request<Body>) -> Result<Response<Body>, Infallible> {
+  let started = Instant::now();
+  let method = req.method().clone();
+  let route = metrics::route_name(&method, req.uri().path());
+
+  let response = handle(state.clone(), req).await;
+  state.metrics.observe_request(&method, route, response.status().as_u16(), started.elapsed());
   Ok(response)
 }
 
-#[tokio::main]
-async fn main() {
-  // We'll bind to 127.0.0.1:3000
-  let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
+async fn handle(state : Arc<AppState>, req : Request<Body>) -> Response<Body> {
+  let path = req.uri().path().to_string();
+
+  match (req.method(), path.as_str()) {
+    (&Method::GET, "/healthz") => healthz(),
+    (&Method::GET, "/readyz") => readyz(state),
+    (&Method::GET, "/metrics") => metrics(state),
+    (&Method::GET, "/echo") => {
+      Response::new(Body::from(req.uri().query().map(|c| c.to_string()).unwrap_or_default()))
+    },
+    (&Method::POST, "/plans") => create_plan(state, req).await,
+    (&Method::POST, "/plans/import") => import_plan(state, req).await,
+    (&Method::GET, "/plans") => list_plans(state, &req),
+    (&Method::GET, p) if p.starts_with("/plans/") => {
+      let id = &p["/plans/".len()..];
+      if let Some(id) = id.strip_suffix("/export") {
+        export_plan(state, id, &req).await
+      } else if let Some(id) = id.strip_suffix("/compare") {
+        compare_plans(state, id, &req).await
+      } else {
+        get_plan(state, id)
+      }
+    },
+    (&Method::DELETE, p) if p.starts_with("/plans/") => delete_plan(state, &p["/plans/".len()..]),
+    (&Method::POST, "/jobs") => create_job(state, req).await,
+    (&Method::GET, p) if p.starts_with("/jobs/") => {
+      let id = &p["/jobs/".len()..];
+      match id.strip_suffix("/events") {
+        Some(id) => watch_job(state, id),
+        None => get_job(state, id),
+      }
+    },
+    (&Method::DELETE, p) if p.starts_with("/jobs/") => delete_job(state, &p["/jobs/".len()..]),
+    (&Method::GET, _) | (&Method::HEAD, _) => serve_static(state, req).await,
+    _ => not_found(),
+  }
+}
+
+#[cfg(unix)]
+async fn shutdown_signal() {
+  use tokio::signal::unix::{signal, SignalKind};
+  let mut terminate = signal(SignalKind::terminate()).expect("Could not listen for SIGTERM");
+  tokio::select! {
+    _ = terminate.recv() => {},
+    _ = tokio::signal::ctrl_c() => {},
+  }
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() {
+  let _ = tokio::signal::ctrl_c().await;
+}
+
+async fn serve(config : Config) {
+  let checkpoint_dir = config.state_dir.join("checkpoints");
+  let plans = match PlanStore::open(config.state_dir.join("plans")) {
+    Ok(plans) => plans,
+    Err(e) => {
+      error!("Could not open the plan store in {}: {}", config.state_dir.display(), e);
+      return;
+    }
+  };
+
+  let state = Arc::new(AppState {
+    blocks: BlockCache::new(&config.data_dir),
+    plans,
+    jobs: JobQueue::new(config.jobs.clone()),
+    data: StaticFiles::files(&config.data_dir),
+    frontend: StaticFiles::site(&config.dist_dir, true),
+    metrics: Metrics::new(),
+  });
+
+  match state.jobs.resume_checkpoints(state.clone(), &checkpoint_dir) {
+    Ok(0) => {},
+    Ok(n) => info!("Resumed {} checkpointed jobs", n),
+    Err(e) => error!("Could not resume checkpointed jobs: {}", e),
+  }
 
   // A `Service` is needed for every connection, so this
-  // creates one from our `hello_world` function.
-  let make_svc = make_service_fn(|_conn| async {
-    // service_fn converts our function into a `Service`
-    Ok::<_, Infallible>(service_fn(hello_world))
+  // creates one from our `route` function.
+  let service_state = state.clone();
+  let make_svc = make_service_fn(move |_conn| {
+    let state = service_state.clone();
+    async move {
+      // service_fn converts our function into a `Service`
+      Ok::<_, Infallible>(service_fn(move |req| route(state.clone(), req)))
+    }
   });
 
-  let server = Server::bind(&addr).serve(make_svc);
+  let server = match Server::try_bind(&config.addr) {
+    Ok(builder) => builder.serve(make_svc),
+    Err(e) => {
+      error!("Could not bind to {}: {}", config.addr, e);
+      return;
+    }
+  };
+  info!("Listening on http://{}", config.addr);
+
+  // Stop taking connections on SIGTERM, and meanwhile let the jobs wind down.
+  // Open event streams end when their jobs do.
+  let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+  let jobs_state = state.clone();
+  let jobs_done = tokio::spawn(async move {
+    shutdown_signal().await;
+    info!("Shutting down");
+    let _ = stop.send(());
+    jobs_state.jobs.shutdown(config.shutdown_timeout, &checkpoint_dir).await
+  });
 
-  // Run this server for... forever!
+  let server = server.with_graceful_shutdown(async {
+    let _ = stopped.await;
+  });
   if let Err(e) = server.await {
-    eprintln!("server error: {}", e);
+    error!("server error: {}", e);
+    return;
+  }
+
+  match jobs_done.await {
+    Ok(Ok(0)) => {},
+    Ok(Ok(n)) => info!("Checkpointed {} unfinished jobs", n),
+    Ok(Err(e)) => error!("Could not checkpoint jobs: {}", e),
+    Err(e) => error!("Could not checkpoint jobs: {}", e),
+  }
+}
+
+fn main() {
+  let args : Vec<String> = env::args().collect();
+  if args.iter().any(|a| a == "--help" || a == "-h") {
+    println!("{}", config::usage());
+    return;
+  }
+
+  let config = match Config::load(&args, env::vars()) {
+    Ok(config) => config,
+    Err(e) => {
+      eprintln!("{}\n\n{}", e, config::usage());
+      std::process::exit(1);
+    }
+  };
+
+  env_logger::Builder::new().filter_level(config.log_level).init();
+  for name in &config.ignored_env {
+    warn!("Ignoring {}, which isn't a setting", name);
+  }
+
+  let mut builder = tokio::runtime::Builder::new();
+  builder.threaded_scheduler().enable_all();
+  if let Some(threads) = config.worker_threads {
+    builder.core_threads(threads.max(1));
+  }
+  let mut runtime = builder.build().expect("Could not start the runtime");
+
+  runtime.block_on(serve(config));
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_query_params() {
+    let params = query_params(Some("with=ab%2Bcd&format=geo+json&empty="));
+    assert_eq!(params["with"], "ab+cd");
+    assert_eq!(params["format"], "geo json");
+    assert_eq!(params["empty"], "");
+    assert!(query_params(None).is_empty());
   }
 }
@@ -0,0 +1,222 @@
+// Counters and histograms for /metrics, in the Prometheus text format.
+//
+// * redistrict_http_requests_total{method,route,status}
+// * redistrict_http_request_duration_seconds{method,route}
+// * redistrict_jobs_queued and redistrict_jobs_running
+// * redistrict_job_duration_seconds{algorithm,state}, from start to finish
+// * redistrict_solver_iterations{algorithm}, for every finished solve
+//
+// Routes are labelled by their pattern (/plans/{id}), not the path, so the
+// number of series stays small.
+//
+// https://prometheus.io/docs/instrumenting/exposition_formats/
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+use hyper::Method;
+
+use crate::jobs::JobState;
+use redistrict_core::solver::Algorithm;
+
+const DURATION_BUCKETS : &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1., 2.5, 5., 10.];
+const JOB_DURATION_BUCKETS : &[f64] = &[0.1, 0.5, 1., 5., 10., 30., 60., 120., 300., 600.];
+const ITERATION_BUCKETS : &[f64] = &[1., 5., 10., 25., 50., 100., 250., 500.];
+
+#[derive(Debug, Clone)]
+struct Histogram {
+  bounds: &'static [f64],
+  // observations in each bucket, not cumulative
+  counts: Vec<u64>,
+  sum: f64,
+  count: u64,
+}
+
+impl Histogram {
+  fn new(bounds : &'static [f64]) -> Self {
+    Self { bounds, counts: vec![0; bounds.len()], sum: 0., count: 0 }
+  }
+
+  fn observe(&mut self, value : f64) {
+    if let Some(i) = self.bounds.iter().position(|&b| value <= b) {
+      self.counts[i] += 1;
+    }
+    self.sum += value;
+    self.count += 1;
+  }
+
+  fn write(&self, out : &mut String, name : &str, labels : &str) {
+    let mut cumulative = 0;
+    for (bound, count) in self.bounds.iter().zip(&self.counts) {
+      cumulative += count;
+      let _ = writeln!(out, "{}_bucket{{{}le=\"{}\"}} {}", name, labels, bound, cumulative);
+    }
+    let _ = writeln!(out, "{}_bucket{{{}le=\"+Inf\"}} {}", name, labels, self.count);
+    let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels.trim_end_matches(','), self.sum);
+    let _ = writeln!(out, "{}_count{{{}}} {}", name, labels.trim_end_matches(','), self.count);
+  }
+}
+
+fn algorithm_name(algorithm : Algorithm) -> &'static str {
+  match algorithm {
+    Algorithm::Power => "power",
+    Algorithm::Lloyd => "lloyd",
+  }
+}
+
+fn state_name(state : JobState) -> &'static str {
+  match state {
+    JobState::Queued => "queued",
+    JobState::Running => "running",
+    JobState::Done => "done",
+    JobState::Failed => "failed",
+    JobState::Cancelled => "cancelled",
+  }
+}
+
+// Clients can send any method they like, so anything unusual shares a label
+fn method_name(method : &Method) -> &'static str {
+  match *method {
+    Method::GET => "GET",
+    Method::POST => "POST",
+    Method::PUT => "PUT",
+    Method::DELETE => "DELETE",
+    Method::HEAD => "HEAD",
+    Method::OPTIONS => "OPTIONS",
+    _ => "other",
+  }
+}
+
+const ROUTES : &[&str] = &["/healthz", "/readyz", "/metrics", "/echo", "/plans", "/plans/import", "/jobs"];
+
+// The route pattern a request matched, for labels
+pub fn route_name(method : &Method, path : &str) -> &'static str {
+  if let Some(route) = ROUTES.iter().find(|&&r| r == path) {
+    return route;
+  }
+  if let Some(rest) = path.strip_prefix("/plans/") {
+    return if rest.ends_with("/export") {
+      "/plans/{id}/export"
+    } else if rest.ends_with("/compare") {
+      "/plans/{id}/compare"
+    } else {
+      "/plans/{id}"
+    };
+  }
+  if let Some(rest) = path.strip_prefix("/jobs/") {
+    return if rest.ends_with("/events") { "/jobs/{id}/events" } else { "/jobs/{id}" };
+  }
+  if method == Method::GET || method == Method::HEAD { "static" } else { "other" }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+  requests: Mutex<BTreeMap<(&'static str, &'static str, u16), u64>>,
+  request_durations: Mutex<BTreeMap<(&'static str, &'static str), Histogram>>,
+  job_durations: Mutex<BTreeMap<(&'static str, &'static str), Histogram>>,
+  iterations: Mutex<BTreeMap<&'static str, Histogram>>,
+}
+
+impl Metrics {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn observe_request(&self, method : &Method, route : &'static str, status : u16, duration : Duration) {
+    let method = method_name(method);
+    *self.requests.lock().unwrap().entry((method, route, status)).or_insert(0) += 1;
+    self.request_durations.lock().unwrap()
+      .entry((method, route))
+      .or_insert_with(|| Histogram::new(DURATION_BUCKETS))
+      .observe(duration.as_secs_f64());
+  }
+
+  pub fn observe_job(&self, algorithm : Algorithm, state : JobState, duration : Duration) {
+    self.job_durations.lock().unwrap()
+      .entry((algorithm_name(algorithm), state_name(state)))
+      .or_insert_with(|| Histogram::new(JOB_DURATION_BUCKETS))
+      .observe(duration.as_secs_f64());
+  }
+
+  pub fn observe_iterations(&self, algorithm : Algorithm, iterations : usize) {
+    self.iterations.lock().unwrap()
+      .entry(algorithm_name(algorithm))
+      .or_insert_with(|| Histogram::new(ITERATION_BUCKETS))
+      .observe(iterations as f64);
+  }
+
+  // Everything in the text format. The queue gauges are read when asked for.
+  pub fn render(&self, jobs_queued : usize, jobs_running : usize) -> String {
+    let mut out = String::new();
+
+    out.push_str("# HELP redistrict_http_requests_total HTTP requests handled.\n");
+    out.push_str("# TYPE redistrict_http_requests_total counter\n");
+    for ((method, route, status), count) in self.requests.lock().unwrap().iter() {
+      let _ = writeln!(out, "redistrict_http_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}", method, route, status, count);
+    }
+
+    out.push_str("# HELP redistrict_http_request_duration_seconds Time to respond to HTTP requests.\n");
+    out.push_str("# TYPE redistrict_http_request_duration_seconds histogram\n");
+    for ((method, route), histogram) in self.request_durations.lock().unwrap().iter() {
+      let labels = format!("method=\"{}\",route=\"{}\",", method, route);
+      histogram.write(&mut out, "redistrict_http_request_duration_seconds", &labels);
+    }
+
+    out.push_str("# HELP redistrict_jobs_queued Jobs waiting for a worker.\n");
+    out.push_str("# TYPE redistrict_jobs_queued gauge\n");
+    let _ = writeln!(out, "redistrict_jobs_queued {}", jobs_queued);
+    out.push_str("# HELP redistrict_jobs_running Jobs being solved.\n");
+    out.push_str("# TYPE redistrict_jobs_running gauge\n");
+    let _ = writeln!(out, "redistrict_jobs_running {}", jobs_running);
+
+    out.push_str("# HELP redistrict_job_duration_seconds Time from a job starting to it finishing.\n");
+    out.push_str("# TYPE redistrict_job_duration_seconds histogram\n");
+    for ((algorithm, state), histogram) in self.job_durations.lock().unwrap().iter() {
+      let labels = format!("algorithm=\"{}\",state=\"{}\",", algorithm, state);
+      histogram.write(&mut out, "redistrict_job_duration_seconds", &labels);
+    }
+
+    out.push_str("# HELP redistrict_solver_iterations Iterations taken by finished solves.\n");
+    out.push_str("# TYPE redistrict_solver_iterations histogram\n");
+    for (algorithm, histogram) in self.iterations.lock().unwrap().iter() {
+      let labels = format!("algorithm=\"{}\",", algorithm);
+      histogram.write(&mut out, "redistrict_solver_iterations", &labels);
+    }
+
+    out
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_route_name() {
+    assert_eq!(route_name(&Method::GET, "/plans"), "/plans");
+    assert_eq!(route_name(&Method::GET, "/plans/abc123"), "/plans/{id}");
+    assert_eq!(route_name(&Method::GET, "/plans/abc123/export"), "/plans/{id}/export");
+    assert_eq!(route_name(&Method::GET, "/jobs/4/events"), "/jobs/{id}/events");
+    assert_eq!(route_name(&Method::GET, "/js/app.js"), "static");
+    assert_eq!(route_name(&Method::POST, "/nowhere"), "other");
+  }
+
+  #[test]
+  fn test_render() {
+    let metrics = Metrics::new();
+    metrics.observe_request(&Method::GET, "/plans", 200, Duration::from_millis(20));
+    metrics.observe_request(&Method::from_bytes(b"BREW").unwrap(), "other", 404, Duration::from_millis(1));
+    metrics.observe_iterations(Algorithm::Power, 12);
+    let text = metrics.render(3, 1);
+
+    assert!(text.contains("redistrict_http_requests_total{method=\"GET\",route=\"/plans\",status=\"200\"} 1\n"));
+    assert!(text.contains("redistrict_http_request_duration_seconds_bucket{method=\"GET\",route=\"/plans\",le=\"0.01\"} 0\n"));
+    assert!(text.contains("redistrict_http_request_duration_seconds_bucket{method=\"GET\",route=\"/plans\",le=\"0.025\"} 1\n"));
+    assert!(text.contains("redistrict_http_request_duration_seconds_count{method=\"GET\",route=\"/plans\"} 1\n"));
+    assert!(text.contains("redistrict_http_requests_total{method=\"other\",route=\"other\",status=\"404\"} 1\n"));
+    assert!(!text.contains("BREW"));
+    assert!(text.contains("redistrict_jobs_queued 3\n"));
+    assert!(text.contains("redistrict_solver_iterations_bucket{algorithm=\"power\",le=\"25\"} 1\n"));
+  }
+}
@@ -0,0 +1,353 @@
+// Plans are kept on disk so they outlive the server and can be shared by id.
+//
+// Each plan is a json file named by its id, next to an index.json holding a
+// summary of every plan for listing. The id is a hash of the map itself (the
+// state and the district of every block), so solving the same map twice
+// gives the same id and only one file.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use redistrict_core::solver::{self, Algorithm, BlockEntry, Solver, SolverConfig, PlanReport};
+use crate::blocks::BlockData;
+use redistrict_core::imports::{Import, ImportFormat};
+
+const INDEX_FILE : &str = "index.json";
+// hex characters of the hash to use for ids
+const ID_LENGTH : usize = 16;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlanRequest {
+  pub state_code: u32,
+  #[serde(flatten)]
+  pub config: SolverConfig,
+}
+
+// Where a plan's map came from
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum PlanSource {
+  // our solver drew it
+  #[default]
+  Solved,
+  // uploaded, like an enacted map. districts are the names the file used.
+  Imported { format: ImportFormat, districts: Vec<String> },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Plan {
+  pub id: String,
+  pub state_code: u32,
+  pub state_name: Option<String>,
+  pub config: SolverConfig,
+  #[serde(default)]
+  pub source: PlanSource,
+  // seconds since the unix epoch
+  pub created: u64,
+  // district index of every block, in block data order
+  pub assignment: Vec<u32>,
+  pub report: PlanReport,
+}
+
+// What the index knows about a plan
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlanSummary {
+  pub id: String,
+  pub state_code: u32,
+  pub state_name: Option<String>,
+  pub num_districts: usize,
+  pub algorithm: Algorithm,
+  pub seed: u64,
+  #[serde(default)]
+  pub source: PlanSource,
+  pub created: u64,
+  pub total_population: u64,
+  pub max_deviation: f64,
+  pub population_spread_percent: f64,
+  pub iterations: usize,
+  pub converged: bool,
+}
+
+// Which plans to list. Anything left as None matches every plan.
+#[derive(Debug, Clone, Default)]
+pub struct PlanFilter {
+  pub state_code: Option<u32>,
+  pub num_districts: Option<usize>,
+  pub algorithm: Option<Algorithm>,
+  // only imported plans, or only solved ones
+  pub imported: Option<bool>,
+}
+
+fn plan_id(state_code : u32, assignment : &[u32]) -> String {
+  let mut hasher = Sha256::new();
+  hasher.update(state_code.to_le_bytes());
+  for district in assignment {
+    hasher.update(district.to_le_bytes());
+  }
+  hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect::<String>()[..ID_LENGTH].to_string()
+}
+
+fn now() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+impl Plan {
+  // the plan for a finished solve of the request's state
+  pub fn new<B : AsRef<[BlockEntry]>>(request : &PlanRequest, data : &BlockData, solver : &Solver<B>) -> Self {
+    Self {
+      id: plan_id(request.state_code, solver.assignment()),
+      state_code: request.state_code,
+      state_name: data.meta.state_name.clone(),
+      config: request.config.clone(),
+      source: PlanSource::Solved,
+      created: now(),
+      assignment: solver.assignment().to_vec(),
+      report: solver.report(),
+    }
+  }
+
+  // an uploaded map of the state, scored like a solved one
+  pub fn imported(state_code : u32, data : &BlockData, format : ImportFormat, import : Import) -> io::Result<Self> {
+    let num_districts = import.districts.len();
+    let report = solver::evaluate(&data.blocks, &import.assignment, num_districts)
+      .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    Ok(Self {
+      id: plan_id(state_code, &import.assignment),
+      state_code,
+      state_name: data.meta.state_name.clone(),
+      config: SolverConfig::new(num_districts),
+      report,
+      source: PlanSource::Imported { format, districts: import.districts },
+      created: now(),
+      assignment: import.assignment,
+    })
+  }
+
+  pub fn summary(&self) -> PlanSummary {
+    PlanSummary {
+      id: self.id.clone(),
+      state_code: self.state_code,
+      state_name: self.state_name.clone(),
+      num_districts: self.config.num_districts,
+      algorithm: self.config.algorithm,
+      seed: self.config.seed,
+      source: self.source.clone(),
+      created: self.created,
+      total_population: self.report.total_population,
+      max_deviation: self.report.max_deviation,
+      population_spread_percent: self.report.population_spread_percent,
+      iterations: self.report.iterations,
+      converged: self.report.converged,
+    }
+  }
+}
+
+impl PlanFilter {
+  pub fn matches(&self, summary : &PlanSummary) -> bool {
+    self.state_code.map(|c| c == summary.state_code).unwrap_or(true)
+      && self.num_districts.map(|k| k == summary.num_districts).unwrap_or(true)
+      && self.algorithm.map(|a| a == summary.algorithm).unwrap_or(true)
+      && self.imported.map(|i| i == (summary.source != PlanSource::Solved)).unwrap_or(true)
+  }
+}
+
+// ids come from clients, so make sure they can't point outside the directory
+fn valid_id(id : &str) -> bool {
+  !id.is_empty() && id.len() <= 64 && id.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+// write to a temporary file first so a crash never leaves half a file behind
+fn write_atomic(path : &Path, bytes : &[u8]) -> io::Result<()> {
+  let tmp = path.with_extension("tmp");
+  fs::write(&tmp, bytes)?;
+  fs::rename(tmp, path)
+}
+
+pub struct PlanStore {
+  dir: PathBuf,
+  index: Mutex<HashMap<String, PlanSummary>>,
+}
+
+impl PlanStore {
+  // Open (or create) the store in this directory
+  pub fn open<P : Into<PathBuf>>(dir : P) -> io::Result<Self> {
+    let dir = dir.into();
+    fs::create_dir_all(&dir)?;
+
+    let index = match fs::read(dir.join(INDEX_FILE)) {
+      Ok(bytes) => serde_json::from_slice::<Vec<PlanSummary>>(&bytes).ok(),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+      Err(e) => return Err(e),
+    };
+
+    let store = Self { dir, index: Mutex::new(HashMap::new()) };
+    match index {
+      Some(summaries) => {
+        *store.index.lock().unwrap() = summaries.into_iter().map(|s| (s.id.clone(), s)).collect();
+      },
+      // missing or unreadable, so work it out from the plans themselves
+      None => store.rebuild_index()?,
+    }
+    Ok(store)
+  }
+
+  fn plan_path(&self, id : &str) -> PathBuf {
+    self.dir.join(format!("{}.json", id))
+  }
+
+  fn rebuild_index(&self) -> io::Result<()> {
+    let mut index = HashMap::new();
+    for entry in fs::read_dir(&self.dir)? {
+      let path = entry?.path();
+      let is_plan = path.file_stem().and_then(|s| s.to_str()).map(valid_id).unwrap_or(false)
+        && path.extension().map(|e| e == "json").unwrap_or(false);
+      if !is_plan {
+        continue;
+      }
+      if let Ok(plan) = serde_json::from_slice::<Plan>(&fs::read(&path)?) {
+        index.insert(plan.id.clone(), plan.summary());
+      }
+    }
+    self.write_index(&index)?;
+    *self.index.lock().unwrap() = index;
+    Ok(())
+  }
+
+  // callers hold the index lock while writing, so writes can't interleave
+  fn write_index(&self, index : &HashMap<String, PlanSummary>) -> io::Result<()> {
+    let mut summaries : Vec<PlanSummary> = index.values().cloned().collect();
+    summaries.sort_by(|a, b| a.created.cmp(&b.created).then_with(|| a.id.cmp(&b.id)));
+    write_atomic(&self.dir.join(INDEX_FILE), &serde_json::to_vec(&summaries)?)
+  }
+
+  // Save the plan. If the same map is already stored, that one is kept.
+  pub fn insert(&self, plan : Plan) -> io::Result<Arc<Plan>> {
+    if let Some(existing) = self.get(&plan.id)? {
+      return Ok(existing);
+    }
+
+    let mut index = self.index.lock().unwrap();
+    write_atomic(&self.plan_path(&plan.id), &serde_json::to_vec(&plan)?)?;
+    index.insert(plan.id.clone(), plan.summary());
+    self.write_index(&index)?;
+    Ok(Arc::new(plan))
+  }
+
+  pub fn get(&self, id : &str) -> io::Result<Option<Arc<Plan>>> {
+    if !valid_id(id) || !self.index.lock().unwrap().contains_key(id) {
+      return Ok(None);
+    }
+    let bytes = fs::read(self.plan_path(id))?;
+    Ok(Some(Arc::new(serde_json::from_slice(&bytes)?)))
+  }
+
+  // summaries of the matching plans, newest first
+  pub fn list(&self, filter : &PlanFilter) -> Vec<PlanSummary> {
+    let mut summaries : Vec<PlanSummary> = self.index.lock().unwrap().values()
+      .filter(|s| filter.matches(s))
+      .cloned()
+      .collect();
+    summaries.sort_by(|a, b| b.created.cmp(&a.created).then_with(|| a.id.cmp(&b.id)));
+    summaries
+  }
+
+  // Returns false if there was no such plan
+  pub fn remove(&self, id : &str) -> io::Result<bool> {
+    let mut index = self.index.lock().unwrap();
+    if !valid_id(id) || index.remove(id).is_none() {
+      return Ok(false);
+    }
+    match fs::remove_file(self.plan_path(id)) {
+      Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+      _ => {},
+    }
+    self.write_index(&index)?;
+    Ok(true)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use redistrict_core::solver::{Center, DistrictReport};
+
+  fn temp_dir(name : &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("redistrict_plans_{}_{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+  }
+
+  fn plan(state_code : u32, assignment : Vec<u32>, created : u64) -> Plan {
+    let district = DistrictReport {
+      population: 10,
+      deviation: 0.,
+      center: Center { coords: (0., 0.), weight: 0. },
+      num_blocks: assignment.len(),
+      moment_of_inertia: 0.,
+    };
+    Plan {
+      id: plan_id(state_code, &assignment),
+      state_code,
+      state_name: None,
+      config: SolverConfig::new(2),
+      source: PlanSource::Solved,
+      created,
+      assignment,
+      report: PlanReport {
+        total_population: 20,
+        target_population: 10.,
+        max_deviation: 0.,
+        population_spread_percent: 0.,
+        iterations: 1,
+        converged: true,
+        districts: vec![district.clone(), district],
+      },
+    }
+  }
+
+  #[test]
+  fn test_ids_follow_content() {
+    assert_eq!(plan_id(37, &[0, 1, 1]), plan_id(37, &[0, 1, 1]));
+    assert_ne!(plan_id(37, &[0, 1, 1]), plan_id(37, &[1, 0, 1]));
+    assert_ne!(plan_id(37, &[0, 1, 1]), plan_id(36, &[0, 1, 1]));
+    assert_eq!(plan_id(37, &[]).len(), ID_LENGTH);
+    assert!(valid_id(&plan_id(37, &[])));
+    assert!(!valid_id("../index"));
+  }
+
+  #[test]
+  fn test_store_round_trip() {
+    let dir = temp_dir("round_trip");
+    let store = PlanStore::open(&dir).unwrap();
+    let a = store.insert(plan(37, vec![0, 1], 1)).unwrap();
+    store.insert(plan(6, vec![1, 0], 2)).unwrap();
+    // the same map again doesn't replace the first
+    let again = store.insert(plan(37, vec![0, 1], 3)).unwrap();
+    assert_eq!(again.created, 1);
+
+    let listed : Vec<u32> = store.list(&PlanFilter::default()).iter().map(|s| s.state_code).collect();
+    assert_eq!(listed, vec![6, 37]);
+    let filter = PlanFilter { state_code: Some(37), ..PlanFilter::default() };
+    assert_eq!(store.list(&filter).len(), 1);
+
+    // it all comes back after a restart
+    let store = PlanStore::open(&dir).unwrap();
+    assert_eq!(store.get(&a.id).unwrap().unwrap().assignment, vec![0, 1]);
+    assert!(store.remove(&a.id).unwrap());
+    assert!(!store.remove(&a.id).unwrap());
+    assert!(store.get(&a.id).unwrap().is_none());
+
+    // and the index can be rebuilt from the plans
+    fs::remove_file(dir.join(INDEX_FILE)).unwrap();
+    let store = PlanStore::open(&dir).unwrap();
+    assert_eq!(store.list(&PlanFilter::default()).len(), 1);
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -0,0 +1,93 @@
+// Colors for drawing districts on a canvas
+
+use serde::{Deserialize, Deserializer};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rgb(pub u8, pub u8, pub u8);
+
+impl Rgb {
+  // "#rgb" or "#rrggbb"
+  pub fn parse(s : &str) -> Option<Self> {
+    let hex = s.trim().strip_prefix('#')?;
+    let digit = |i : usize| u8::from_str_radix(hex.get(i..i + 1)?, 16).ok();
+    let pair = |i : usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+    match hex.len() {
+      3 => Some(Rgb(digit(0)? * 17, digit(1)? * 17, digit(2)? * 17)),
+      6 => Some(Rgb(pair(0)?, pair(2)?, pair(4)?)),
+      _ => None,
+    }
+  }
+
+  pub fn css(&self, alpha : f64) -> String {
+    format!("rgba({}, {}, {}, {})", self.0, self.1, self.2, alpha)
+  }
+
+  // t of the way from self to other
+  pub fn mix(&self, other : Rgb, t : f64) -> Rgb {
+    let t = t.clamp(0., 1.);
+    let lerp = |a : u8, b : u8| (a as f64 + (b as f64 - a as f64) * t).round() as u8;
+    Rgb(lerp(self.0, other.0), lerp(self.1, other.1), lerp(self.2, other.2))
+  }
+
+  // black or white, whichever stands out more against this (by luma)
+  pub fn contrast(&self) -> Rgb {
+    let luma = 0.299 * self.0 as f64 + 0.587 * self.1 as f64 + 0.114 * self.2 as f64;
+    if luma > 150. { BLACK } else { WHITE }
+  }
+}
+
+// Tableau 10
+pub const DEFAULT_PALETTE : &[Rgb] = &[
+  Rgb(0x4e, 0x79, 0xa7),
+  Rgb(0xf2, 0x8e, 0x2b),
+  Rgb(0xe1, 0x57, 0x59),
+  Rgb(0x76, 0xb7, 0xb2),
+  Rgb(0x59, 0xa1, 0x4f),
+  Rgb(0xed, 0xc9, 0x48),
+  Rgb(0xb0, 0x7a, 0xa1),
+  Rgb(0xff, 0x9d, 0xa7),
+  Rgb(0x9c, 0x75, 0x5f),
+  Rgb(0xba, 0xb0, 0xac),
+];
+
+pub const WHITE : Rgb = Rgb(0xff, 0xff, 0xff);
+pub const BLACK : Rgb = Rgb(0, 0, 0);
+
+// palettes come from js as a list of css hex colors
+pub fn deserialize_palette<'de, D : Deserializer<'de>>(deserializer : D) -> Result<Option<Vec<Rgb>>, D::Error> {
+  let colors : Option<Vec<String>> = Option::deserialize(deserializer)?;
+  colors.map(|colors| {
+    colors.iter().map(|c| {
+      Rgb::parse(c).ok_or_else(|| serde::de::Error::custom(format!("{} isn't a #rrggbb color", c)))
+    }).collect()
+  }).transpose()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse() {
+    assert_eq!(Rgb::parse("#4e79a7"), Some(Rgb(0x4e, 0x79, 0xa7)));
+    assert_eq!(Rgb::parse("#fff"), Some(WHITE));
+    assert_eq!(Rgb::parse("red"), None);
+    assert_eq!(Rgb::parse("#12345"), None);
+  }
+
+  #[test]
+  fn test_mix() {
+    assert_eq!(WHITE.mix(BLACK, 0.), WHITE);
+    assert_eq!(WHITE.mix(BLACK, 1.), BLACK);
+    assert_eq!(Rgb(0, 100, 200).mix(Rgb(100, 200, 0), 0.5), Rgb(50, 150, 100));
+  }
+
+  #[test]
+  fn test_contrast() {
+    assert_eq!(WHITE.contrast(), BLACK);
+    assert_eq!(BLACK.contrast(), WHITE);
+    // the default blue and yellow
+    assert_eq!(DEFAULT_PALETTE[0].contrast(), WHITE);
+    assert_eq!(DEFAULT_PALETTE[5].contrast(), BLACK);
+  }
+}
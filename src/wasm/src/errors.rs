@@ -0,0 +1,96 @@
+// Why a Redistricter couldn't be made.
+//
+// These reach js as an `Error` whose `kind` is one of "network", "http",
+// "parse", "empty_data", "non_finite_coordinates" or "length_mismatch", with
+// the details (status, line and column, block index) as extra fields.
+
+use std::fmt;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use js_sys::Reflect;
+use redistrict_core::BlockError;
+
+#[derive(Debug, Clone)]
+pub enum LoadError {
+  // fetch itself failed (offline, CORS, bad url)
+  Network { url: String, message: String },
+  // the server answered, but not with a 2xx
+  Http { url: String, status: u16, status_text: String },
+  // the body isn't block data
+  Parse { message: String, line: usize, column: usize },
+  Blocks(BlockError),
+  // from_arrays was given arrays of different lengths
+  LengthMismatch { xs: u32, ys: u32, pops: u32 },
+}
+
+impl LoadError {
+  pub fn kind(&self) -> &'static str {
+    match self {
+      LoadError::Network { .. } => "network",
+      LoadError::Http { .. } => "http",
+      LoadError::Parse { .. } => "parse",
+      LoadError::Blocks(BlockError::Empty) => "empty_data",
+      LoadError::Blocks(BlockError::NonFinite { .. }) => "non_finite_coordinates",
+      LoadError::LengthMismatch { .. } => "length_mismatch",
+    }
+  }
+
+  pub fn network(url : &str, error : JsValue) -> Self {
+    let message = error.dyn_ref::<js_sys::Error>()
+      .map(|e| String::from(e.message()))
+      .or_else(|| error.as_string())
+      .unwrap_or_else(|| "fetch failed".to_string());
+    LoadError::Network { url: url.to_string(), message }
+  }
+}
+
+impl fmt::Display for LoadError {
+  fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      LoadError::Network { url, message } => write!(f, "Could not fetch {}: {}", url, message),
+      LoadError::Http { url, status, status_text } => write!(f, "Could not fetch {}: {} {}", url, status, status_text),
+      LoadError::Parse { message, .. } => write!(f, "Could not read the block data: {}", message),
+      LoadError::Blocks(e) => write!(f, "{}", e),
+      LoadError::LengthMismatch { xs, ys, pops } => {
+        write!(f, "xs, ys and pops should be the same length, not {}, {} and {}", xs, ys, pops)
+      },
+    }
+  }
+}
+
+impl From<BlockError> for LoadError {
+  fn from(e : BlockError) -> Self {
+    LoadError::Blocks(e)
+  }
+}
+
+impl From<serde_json::Error> for LoadError {
+  fn from(e : serde_json::Error) -> Self {
+    LoadError::Parse { message: e.to_string(), line: e.line(), column: e.column() }
+  }
+}
+
+impl From<LoadError> for JsValue {
+  fn from(e : LoadError) -> Self {
+    let error = js_sys::Error::new(&e.to_string());
+    let mut fields : Vec<(&str, JsValue)> = vec![("kind", e.kind().into())];
+    match &e {
+      LoadError::Network { url, .. } => fields.push(("url", url.into())),
+      LoadError::Http { url, status, .. } => {
+        fields.push(("url", url.into()));
+        fields.push(("status", (*status).into()));
+      },
+      LoadError::Parse { line, column, .. } => {
+        fields.push(("line", (*line as u32).into()));
+        fields.push(("column", (*column as u32).into()));
+      },
+      LoadError::Blocks(BlockError::NonFinite { index }) => fields.push(("index", (*index as u32).into())),
+      _ => {},
+    }
+    for (name, value) in fields {
+      // setting a property on a fresh Error can't fail
+      let _ = Reflect::set(&error, &name.into(), &value);
+    }
+    error.into()
+  }
+}
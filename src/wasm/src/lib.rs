@@ -24,9 +24,12 @@ pub fn browser_debug() {
   console_error_panic_hook::set_once();
 }
 
-// mod eb_tech;
-mod stats;
-pub use stats::*;
+// the algorithms live in redistrict-core. This crate just binds them to js.
+mod colors;
+mod errors;
+pub use errors::LoadError;
 mod redistricter;
 pub use redistricter::*;
+mod session;
+pub use session::*;
 // pub mod simplex;
@@ -2,10 +2,18 @@ use super::*;
 use wasm_bindgen_futures::{JsFuture};
 use wasm_bindgen::JsCast;
 use web_sys::{Response};
-use web_sys::console;
-use geo::{Point, LineString, Coordinate, Rect};
-use geo::algorithm::bounding_rect::BoundingRect;
+use geo::{Coordinate, Rect};
 use std::f64::consts::PI;
+use js_sys::{Atomics, Float64Array, Function, Int32Array, Uint32Array};
+use redistrict_core::{check_blocks, solver, BlockEntry, Center, PlanReport, RunningStatistics, RunningStatisticsResults};
+use redistrict_core::{Solver, SolverConfig};
+use redistrict_core::solver::StepReport;
+use redistrict_core::balance::BalanceProgress;
+use redistrict_core::coloring;
+use redistrict_core::geometry::Ring;
+use redistrict_core::power;
+use crate::colors::{self, Rgb, DEFAULT_PALETTE, WHITE};
+use redistrict_core::imports::{self, ImportFormat};
 
 const PI2 : f64 = 2. * PI;
 
@@ -38,37 +46,205 @@ fn draw_circle(context : &web_sys::CanvasRenderingContext2d, p : Coordinate<f64>
   context.stroke();
 }
 
-fn get_random_coordinate(b : Rect<f64>) -> Coordinate<f64> {
-  let x = b.width() * rand::random::<f64>() + b.min.x;
-  let y = b.height() * rand::random::<f64>() + b.min.y;
-  Coordinate { x, y }
+// (x, y) pairs as x0, y0, x1, y1, ...
+fn interleave<I : Iterator<Item = (f64, f64)>>(points : I, len : usize) -> Vec<f64> {
+  let mut out = Vec::with_capacity(2 * len);
+  for (x, y) in points {
+    out.push(x);
+    out.push(y);
+  }
+  out
 }
 
-fn distance_block_to_center(b : &BlockEntry, c: &Center) -> f64 {
-  use geo::algorithm::euclidean_distance::EuclideanDistance;
-  Point::from(c.coords).euclidean_distance(&Point::from(b.coords))
+// Whoever started a long solve can stop it by setting the first element of
+// this (an Int32Array over a SharedArrayBuffer) to anything but 0
+fn is_cancelled(flag : &Option<Int32Array>) -> bool {
+  flag.as_ref().map(|f| Atomics::load(f, 0).map(|v| v != 0).unwrap_or(false)).unwrap_or(false)
 }
 
-#[wasm_bindgen]
-#[derive(Debug, Clone)]
-pub struct BlockEntry {
-  coords: (f64, f64),
-  population: u32,
+// Serialize for js. A failure comes back as a js Error rather than a panic.
+fn to_js<T : Serialize>(value : &T) -> Result<JsValue, JsValue> {
+  JsValue::from_serde(value).map_err(|e| js_sys::Error::new(&format!("Could not convert to js: {}", e)).into())
 }
 
-#[wasm_bindgen]
-#[derive(Debug, Clone)]
-pub struct Center {
-  coords: (f64, f64),
-  weight: f64,
+// Hand progress to the callback, if there is one. Returning false from it
+// stops the solve too, but only a plain false: a callback proxied by comlink
+// (as from the worker) returns a Promise, which never counts, so there only
+// the cancel flag stops it. Anything it throws, or a progress that can't be
+// converted, is kept to be rethrown.
+fn report_progress<T : Serialize>(callback : &Option<Function>, progress : &T, error : &mut Option<JsValue>) -> bool {
+  let callback = match callback {
+    Some(callback) => callback,
+    None => return true,
+  };
+  let progress = match to_js(progress) {
+    Ok(progress) => progress,
+    Err(e) => {
+      *error = Some(e);
+      return false;
+    },
+  };
+  match callback.call1(&JsValue::NULL, &progress) {
+    Ok(result) => result != JsValue::FALSE,
+    Err(e) => {
+      *error = Some(e);
+      false
+    },
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BoundingBox {
+  min_x: f64,
+  min_y: f64,
+  max_x: f64,
+  max_y: f64,
+}
+
+impl From<&BoundingBox> for Rect<f64> {
+  fn from(b : &BoundingBox) -> Self {
+    Rect::new(
+      Coordinate { x: b.min_x, y: b.min_y },
+      Coordinate { x: b.max_x, y: b.max_y }
+    )
+  }
+}
+
+// The summary that data-prep writes after the blocks
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockDataMeta {
+  state_code: String,
+  state_name: Option<String>,
+  vintage: Option<u32>,
+  block_count: usize,
+  total_population: u64,
+  bounding_box: BoundingBox,
+  projection: String,
+  population: RunningStatisticsResults,
+}
+
+impl BlockDataMeta {
+  // What we can tell about blocks that didn't come from data-prep
+  fn from_blocks(blocks : &[BlockEntry]) -> Self {
+    let mut population = RunningStatistics::new();
+    let mut bounding_box = BoundingBox {
+      min_x: f64::INFINITY,
+      min_y: f64::INFINITY,
+      max_x: f64::NEG_INFINITY,
+      max_y: f64::NEG_INFINITY,
+    };
+    for b in blocks {
+      population.push(b.population as f64);
+      bounding_box.min_x = bounding_box.min_x.min(b.coords.0);
+      bounding_box.min_y = bounding_box.min_y.min(b.coords.1);
+      bounding_box.max_x = bounding_box.max_x.max(b.coords.0);
+      bounding_box.max_y = bounding_box.max_y.max(b.coords.1);
+    }
+    if blocks.is_empty() {
+      bounding_box = BoundingBox { min_x: 0., min_y: 0., max_x: 0., max_y: 0. };
+    }
+
+    Self {
+      state_code: String::new(),
+      state_name: None,
+      vintage: None,
+      block_count: blocks.len(),
+      total_population: population.sum() as u64,
+      bounding_box,
+      projection: String::new(),
+      population: population.as_results(),
+    }
+  }
+}
+
+#[derive(Debug, Deserialize)]
+struct BlockData {
+  blocks: Vec<(f64, f64, u32)>,
+  meta: BlockDataMeta,
+}
+
+// What import_plan hands back to js
+#[derive(Debug, Serialize)]
+struct ImportedPlan {
+  assignment: Vec<u32>,
+  districts: Vec<String>,
+  unmatched: usize,
+  report: PlanReport,
 }
 
 #[wasm_bindgen]
 pub struct Redistricter {
-  blocks: Vec<BlockEntry>,
+  meta: BlockDataMeta,
   bounding_rect: Rect<f64>,
-  num_centers: usize,
-  centers: Vec<Center>,
+  // what reset solves with. A seed of 0 means a new random one every time.
+  config: SolverConfig,
+  solver: Solver<Vec<BlockEntry>>,
+  // pairs of neighbouring blocks, if js has given us them
+  block_adjacency: Option<Vec<(usize, usize)>>,
+  // the color of every district when last drawn or exported, to keep them
+  // steady between iterations
+  colors: Vec<usize>,
+  // whether the districts have changed since the colors were worked out.
+  // Finding neighbours goes over every block, so it's not done every draw.
+  colors_stale: bool,
+  // convex hull of the blocks, to clip power diagram cells to
+  hull: Ring,
+}
+
+// What export hands back to js: enough to save the plan and pick it up again
+#[derive(Debug, Serialize)]
+struct ExportedPlan<'a> {
+  state_code: &'a str,
+  config: &'a SolverConfig,
+  centers: &'a [Center],
+  colors: &'a [usize],
+  // power diagram cells, clipped to the hull of the blocks
+  cells: Vec<Ring>,
+  assignment: &'a [u32],
+  report: PlanReport,
+}
+
+// How draw_districts draws. Everything is optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct DrawOptions {
+  // css hex colors, for each color number (see district_colors). They repeat
+  // if there are too few.
+  #[serde(deserialize_with = "colors::deserialize_palette")]
+  palette: Option<Vec<Rgb>>,
+  // fade emptier blocks towards white, like the js populationColorScale
+  shade_by_population: bool,
+  block_radius: Option<f64>,
+  // outline the power diagram cells, the boundaries the solver assigns by
+  cells: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct CellOptions {
+  // "hull" (the default) or "bounding_box"
+  clip: Option<String>,
+}
+
+// how many steps of population shading there are
+const SHADES : usize = 8;
+
+// What solve and balance hand back to js
+#[derive(Debug, Serialize)]
+struct SolveResult<P> {
+  // stopped early by the callback or the cancel flag
+  cancelled: bool,
+  progress: P,
+  report: PlanReport,
+}
+
+// What step hands back to js
+#[derive(Debug, Serialize)]
+struct StepDiagnostic {
+  #[serde(flatten)]
+  step: StepReport,
+  converged: bool,
+  elapsed_ms: f64,
 }
 
 #[wasm_bindgen]
@@ -103,49 +279,170 @@ impl Redistricter {
   //   this
   // }
 
-  // https://github.com/rustwasm/wasm-bindgen/issues/1858
-  pub async fn create( state_code : u32 ) -> Result<Redistricter, JsValue> {
+  fn from_blocks(blocks : Vec<BlockEntry>, meta : BlockDataMeta) -> Self {
+    let config = SolverConfig::new(5);
+    let hull = power::hull(&blocks);
+    let mut this = Self {
+      bounding_rect: Rect::from(&meta.bounding_box),
+      meta,
+      solver: Solver::new(blocks, config.clone()),
+      config,
+      block_adjacency: None,
+      colors: vec![],
+      colors_stale: true,
+      hull,
+    };
 
-    let url = format!("/block_data_state_{}.json", state_code);
-    let window = web_sys::window().unwrap();
+    this.reset();
 
-    let resp_value = JsFuture::from(window.fetch_with_str(&url)).await?;
+    this
+  }
 
-    let resp: Response = resp_value.dyn_into().unwrap();
+  // Blocks from memory: x and y in any projected coordinates, and populations.
+  // The arrays are copied, so js keeps (or can transfer away) its buffers.
+  pub fn from_arrays(xs : &Float64Array, ys : &Float64Array, pops : &Uint32Array) -> Result<Redistricter, JsValue> {
+    if xs.length() != ys.length() || xs.length() != pops.length() {
+      return Err(LoadError::LengthMismatch { xs: xs.length(), ys: ys.length(), pops: pops.length() }.into());
+    }
 
-      // Convert this other `Promise` into a rust `Future`.
-    let json = JsFuture::from(resp.json()?).await?;
+    let blocks : Vec<BlockEntry> = xs.to_vec().into_iter()
+      .zip(ys.to_vec())
+      .zip(pops.to_vec())
+      .map(|((x, y), population)| BlockEntry { coords: (x, y), population })
+      .collect();
+    check_blocks(&blocks).map_err(LoadError::from)?;
 
-    let blocks : Vec<(f64, f64, u32)> = json.into_serde().unwrap();
-    // get the bounding rect for these points
-    let linestring = LineString(blocks.iter().map(|b| Coordinate { x: b.0, y: b.1 }).collect());
-    let bounding_rect = linestring.bounding_rect().unwrap();
+    let meta = BlockDataMeta::from_blocks(&blocks);
+    Ok(Self::from_blocks(blocks, meta))
+  }
 
-    let first = &blocks[0];
-    console::log_4(&"First Entry: ".into(), &first.0.into(), &first.1.into(), &first.2.into());
-    let mut this = Self {
-      bounding_rect,
-      num_centers: 5,
-      centers: vec![],
-      blocks: blocks.iter().map(|b| BlockEntry {
-        coords: (b.0, b.1),
-        population: b.2,
-      }).collect(),
+  // Blocks from a block data file (as data-prep writes them) anywhere. Works
+  // in workers as well as windows.
+  // https://github.com/rustwasm/wasm-bindgen/issues/1858
+  pub async fn from_url(url : String) -> Result<Redistricter, JsValue> {
+    let network_error = |e : JsValue| LoadError::network(&url, e);
+
+    let global = js_sys::global();
+    let request = match global.dyn_ref::<web_sys::Window>() {
+      Some(window) => window.fetch_with_str(&url),
+      None => global.dyn_into::<web_sys::WorkerGlobalScope>().map_err(|g| network_error(g.into()))?.fetch_with_str(&url),
     };
+    let resp : Response = JsFuture::from(request).await.map_err(network_error)?
+      .dyn_into().map_err(network_error)?;
+    if !resp.ok() {
+      return Err(LoadError::Http { url: url.clone(), status: resp.status(), status_text: resp.status_text() }.into());
+    }
 
-    this.reset();
+    // read it as text so parse errors can say where they are
+    let text = JsFuture::from(resp.text().map_err(network_error)?).await.map_err(network_error)?
+      .as_string()
+      .unwrap_or_default();
+    let BlockData { blocks, meta } = serde_json::from_str(&text).map_err(LoadError::from)?;
 
-    Ok(this)
+    let blocks : Vec<BlockEntry> = blocks.iter().map(|b| BlockEntry {
+      coords: (b.0, b.1),
+      population: b.2,
+    }).collect();
+    check_blocks(&blocks).map_err(LoadError::from)?;
+
+    Ok(Self::from_blocks(blocks, meta))
+  }
+
+  // The state's block data from the page's origin
+  pub async fn create( state_code : u32 ) -> Result<Redistricter, JsValue> {
+    Self::from_url(format!("/block_data_state_{:02}.json", state_code)).await
   }
 
+  // Start over from new seeded centers
   pub fn reset(&mut self){
-    self.centers = vec![];
-    for _i in 0..self.num_centers {
-      self.centers.push(Center {
-        coords: get_random_coordinate(self.bounding_rect).x_y(),
-        weight: 0.,
-      })
+    let placeholder = Solver::new(vec![], SolverConfig::new(1));
+    let blocks = std::mem::replace(&mut self.solver, placeholder).into_blocks();
+    let seed = if self.config.seed == 0 { rand::random() } else { self.config.seed };
+    let config = SolverConfig { seed, ..self.config.clone() };
+    self.solver = Solver::new(blocks, config);
+    self.solver.assign();
+    self.colors_stale = true;
+  }
+
+  // One assign and relocate iteration. Returns { iteration, max_movement,
+  // total_cost, max_deviation, converged, elapsed_ms }. Call it until
+  // is_converged(), a few per animation frame.
+  pub fn step(&mut self) -> Result<JsValue, JsValue> {
+    let started = js_sys::Date::now();
+    let step = self.solver.step();
+    // leave the assignment matching the centers we draw. The weights were
+    // balanced in step, so there's no need to do that again.
+    self.solver.assign_by_power();
+    self.colors_stale = true;
+
+    let diagnostic = StepDiagnostic {
+      step,
+      converged: self.solver.is_converged(),
+      elapsed_ms: js_sys::Date::now() - started,
+    };
+    to_js(&diagnostic)
+  }
+
+  // Run to convergence in one go, calling progress(step) every `every`
+  // iterations with what step() would return. Stops early if progress returns
+  // false (when called directly, see report_progress) or the cancel flag is
+  // set, keeping the centers it got to. Returns { cancelled, progress, report }.
+  pub fn solve(&mut self, every : u32, progress : Option<Function>, cancel : Option<Int32Array>) -> Result<JsValue, JsValue> {
+    let every = every.max(1) as usize;
+    let mut error = None;
+    let mut last = None;
+    let mut started = js_sys::Date::now();
+    self.colors_stale = true;
+    let completed = self.solver.solve_with(|solver, step| {
+      let diagnostic = StepDiagnostic {
+        step: step.clone(),
+        converged: solver.is_converged(),
+        elapsed_ms: js_sys::Date::now() - started,
+      };
+      let keep_going = step.iteration % every != 0 || report_progress(&progress, &diagnostic, &mut error);
+      last = Some(diagnostic);
+      // time the next step from here, leaving out the callback
+      started = js_sys::Date::now();
+      keep_going && !is_cancelled(&cancel)
+    });
+    if let Some(e) = error {
+      return Err(e);
     }
+    if !completed {
+      self.solver.assign();
+    }
+
+    let result = SolveResult { cancelled: !completed, progress: last, report: self.solver.report() };
+    to_js(&result)
+  }
+
+  // Balance the populations for the current centers with a min cost flow, to
+  // within a block (see balance in redistrict-core). This is slow on a big state, so progress({ augmentations,
+  // routed_population, total_population }) is called every `every` augmenting
+  // paths, and it stops like solve does. When stopped, blocks not yet routed
+  // keep their nearest center. Returns { cancelled, progress, report }.
+  pub fn balance(&mut self, every : u32, progress : Option<Function>, cancel : Option<Int32Array>) -> Result<JsValue, JsValue> {
+    let mut error = None;
+    let mut last = BalanceProgress::default();
+    self.colors_stale = true;
+    let completed = self.solver.balance_with(every.max(1) as usize, |p| {
+      last = p.clone();
+      report_progress(&progress, p, &mut error) && !is_cancelled(&cancel)
+    });
+    if let Some(e) = error {
+      return Err(e);
+    }
+
+    let result = SolveResult { cancelled: !completed, progress: last, report: self.solver.report() };
+    to_js(&result)
+  }
+
+  pub fn is_converged(&self) -> bool {
+    self.solver.is_converged()
+  }
+
+  pub fn iteration(&self) -> usize {
+    self.solver.iteration()
   }
 
   fn to_canvas_coord(&self, canvas : &web_sys::HtmlCanvasElement, p : Coordinate<f64>) -> Coordinate<f64> {
@@ -169,38 +466,280 @@ impl Redistricter {
     self.bounding_rect.height()
   }
 
+  pub fn total_population(&self) -> f64 {
+    self.meta.total_population as f64
+  }
+
+  // The report for the current assignment: district populations, deviation,
+  // compactness and so on
+  pub fn report(&self) -> Result<JsValue, JsValue> {
+    to_js(&self.solver.report())
+  }
+
+  // { state_code, config, centers, assignment, report }
+  pub fn export(&mut self) -> Result<JsValue, JsValue> {
+    self.update_colors();
+    let plan = ExportedPlan {
+      state_code: &self.meta.state_code,
+      config: &self.config,
+      centers: self.solver.centers(),
+      colors: &self.colors,
+      cells: power::power_cells(self.solver.centers(), &self.hull),
+      assignment: self.solver.assignment(),
+      report: self.solver.report(),
+    };
+    to_js(&plan)
+  }
+
+  pub fn meta(&self) -> Result<JsValue, JsValue> {
+    to_js(&self.meta)
+  }
+
+  // { num_districts, algorithm, seed, max_iterations, tolerance }, all but
+  // num_districts optional. Starts over.
+  pub fn configure(&mut self, config : JsValue) -> Result<(), JsValue> {
+    let config : SolverConfig = config.into_serde().map_err(|e| JsValue::from_str(&e.to_string()))?;
+    if config.num_districts == 0 {
+      return Err("num_districts should be at least 1".into());
+    }
+    self.config = config;
+    self.reset();
+    Ok(())
+  }
+
   pub fn set_num_centers(&mut self, n : usize){
-    self.num_centers = n;
+    self.config.num_districts = n;
     self.reset();
   }
 
   pub fn num_blocks(&self) -> usize {
-    self.blocks.len()
+    self.solver.blocks().len()
   }
 
-  pub fn get_block(&self, n : usize) -> Option<BlockEntry> {
-    self.blocks.get(n).map(|b| b.clone())
+  // { coords, population }, or undefined past the end
+  pub fn get_block(&self, n : usize) -> Result<JsValue, JsValue> {
+    self.solver.blocks().get(n).map(to_js).unwrap_or(Ok(JsValue::UNDEFINED))
   }
 
   pub fn num_centers(&self) -> usize {
-    self.centers.len()
+    self.solver.centers().len()
+  }
+
+  // { coords, weight }, or undefined past the end
+  pub fn get_centers(&self, n : usize) -> Result<JsValue, JsValue> {
+    self.solver.centers().get(n).map(to_js).unwrap_or(Ok(JsValue::UNDEFINED))
+  }
+
+  // The bulk accessors below hand js a copy in one go rather than a view into
+  // wasm memory, since views go stale whenever that memory grows.
+
+  // x0, y0, x1, y1, ... for every block
+  pub fn block_coords(&self) -> Float64Array {
+    let blocks = self.solver.blocks();
+    Float64Array::from(&interleave(blocks.iter().map(|b| b.coords), blocks.len())[..])
   }
 
-  pub fn get_centers(&self, n : usize) -> Option<Center> {
-    self.centers.get(n).map(|b| b.clone())
+  pub fn block_populations(&self) -> Uint32Array {
+    let populations : Vec<u32> = self.solver.blocks().iter().map(|b| b.population).collect();
+    Uint32Array::from(&populations[..])
+  }
+
+  // x0, y0, x1, y1, ... for every center
+  pub fn center_coords(&self) -> Float64Array {
+    let centers = self.solver.centers();
+    Float64Array::from(&interleave(centers.iter().map(|c| c.coords), centers.len())[..])
+  }
+
+  pub fn center_weights(&self) -> Float64Array {
+    let weights : Vec<f64> = self.solver.centers().iter().map(|c| c.weight).collect();
+    Float64Array::from(&weights[..])
+  }
+
+  // the district (center index) of every block
+  pub fn assignment(&self) -> Uint32Array {
+    Uint32Array::from(self.solver.assignment())
+  }
+
+  // the population of every district. As f64 since js has no u64 array.
+  pub fn district_populations(&self) -> Float64Array {
+    let populations : Vec<f64> = self.solver.populations().iter().map(|&p| p as f64).collect();
+    Float64Array::from(&populations[..])
+  }
+
+  // Score a plan drawn elsewhere, like the enacted map. format is "baf" (which
+  // also needs the block GEOIDs, in block data order) or "geojson". Returns
+  // { assignment, districts, unmatched, report }.
+  pub fn import_plan(&self, format : &str, text : &str, geoids : JsValue) -> Result<JsValue, JsValue> {
+    let blocks = self.solver.blocks();
+    let import = match ImportFormat::from_name(format) {
+      Some(ImportFormat::Baf) => {
+        let geoids : Vec<String> = geoids.into_serde().map_err(|e| JsValue::from_str(&e.to_string()))?;
+        if geoids.len() != blocks.len() {
+          return Err("The block GEOIDs don't match the block data".into());
+        }
+        imports::import_baf(text, &geoids)
+      },
+      Some(ImportFormat::GeoJson) => imports::import_geojson(text.as_bytes(), blocks),
+      None => return Err("format should be baf or geojson".into()),
+    }.map_err(|e| JsValue::from_str(&e.to_string()))?;
+
+    let report = solver::evaluate(blocks, &import.assignment, import.districts.len())
+      .map_err(|e| JsValue::from_str(&e.to_string()))?;
+    let plan = ImportedPlan {
+      assignment: import.assignment,
+      districts: import.districts,
+      unmatched: import.unmatched,
+      report,
+    };
+    to_js(&plan)
   }
 
   pub fn draw_blocks(&self, context : &web_sys::CanvasRenderingContext2d) {
     let canvas = &context.canvas().unwrap();
-    self.blocks.iter().for_each(|b| {
+    self.solver.blocks().iter().for_each(|b| {
       let coord = self.to_canvas_coord(canvas, b.coords.into());
       draw_disc(context, coord, 1., &"#fff".into())
     });
   }
 
+  // Neighbouring blocks, as pairs of block indices a0, b0, a1, b1, ... (the
+  // block_adjacency file data-prep writes, not the topology's geometry
+  // indices, which count blocks with no population too). With them, districts are told
+  // apart by which blocks touch rather than by the power diagram.
+  pub fn set_block_adjacency(&mut self, pairs : &Uint32Array) -> Result<(), JsValue> {
+    let pairs = pairs.to_vec();
+    let num_blocks = self.solver.blocks().len();
+    if pairs.len() % 2 != 0 || pairs.iter().any(|&b| b as usize >= num_blocks) {
+      return Err("Block adjacency should be pairs of block indices".into());
+    }
+    self.block_adjacency = Some(pairs.chunks(2).map(|p| (p[0] as usize, p[1] as usize)).collect());
+    self.colors_stale = true;
+    Ok(())
+  }
+
+  // A color number for every district. Neighbouring districts never share
+  // one, and few are used (usually 4 or 5), so any palette that long works.
+  pub fn district_colors(&mut self) -> Uint32Array {
+    self.update_colors();
+    let colors : Vec<u32> = self.colors.iter().map(|&c| c as u32).collect();
+    Uint32Array::from(&colors[..])
+  }
+
+  // Blocks in the color of their district, and centers outlined in it with a
+  // black or white fill, whichever contrasts more.
+  // options: { palette: ["#4e79a7", ...], shade_by_population, block_radius, cells }
+  pub fn draw_districts(&mut self, context : &web_sys::CanvasRenderingContext2d, options : JsValue) -> Result<(), JsValue> {
+    let options : DrawOptions = if options.is_undefined() || options.is_null() {
+      DrawOptions::default()
+    } else {
+      options.into_serde().map_err(|e| JsValue::from_str(&e.to_string()))?
+    };
+    let palette = options.palette.as_deref().filter(|p| !p.is_empty()).unwrap_or(DEFAULT_PALETTE);
+    let radius = options.block_radius.unwrap_or(1.);
+    let canvas = &context.canvas().unwrap();
+    self.update_colors();
+    let blocks = self.solver.blocks();
+    let assignment = self.solver.assignment();
+    let colors = &self.colors;
+
+    let shades = if options.shade_by_population { SHADES } else { 1 };
+    let max_population = blocks.iter().map(|b| b.population).max().unwrap_or(0).max(1) as f64;
+    let style = |i : usize| {
+      let shade = (blocks[i].population as f64 / max_population * (shades - 1) as f64).ceil() as usize;
+      (colors[assignment[i] as usize] % palette.len()) * shades + shade
+    };
+
+    // setting the fill style is slow, so fill every block of a color as one path
+    let mut order : Vec<usize> = (0..blocks.len()).collect();
+    order.sort_by_key(|&i| style(i));
+    let mut start = 0;
+    while start < order.len() {
+      let current = style(order[start]);
+      let end = order[start..].iter().position(|&i| style(i) != current).map_or(order.len(), |n| start + n);
+
+      let color = palette[current / shades];
+      let color = if shades == 1 {
+        color.css(1.)
+      } else {
+        let t = (current % shades) as f64 / (shades - 1) as f64;
+        WHITE.mix(color, t).css(0.4 + 0.6 * t)
+      };
+      context.set_fill_style(&color.into());
+      context.begin_path();
+      for &i in &order[start..end] {
+        let coord = self.to_canvas_coord(canvas, blocks[i].coords.into());
+        context.move_to(coord.x + radius, coord.y);
+        context.arc(coord.x, coord.y, radius, 0., PI2)?;
+      }
+      context.fill();
+      start = end;
+    }
+
+    if options.cells {
+      context.set_stroke_style(&"rgba(0, 0, 0, 0.6)".into());
+      for cell in power::power_cells(self.solver.centers(), &self.hull) {
+        context.begin_path();
+        for (n, &p) in cell.iter().enumerate() {
+          let coord = self.to_canvas_coord(canvas, p.into());
+          if n == 0 { context.move_to(coord.x, coord.y) } else { context.line_to(coord.x, coord.y) }
+        }
+        context.close_path();
+        context.stroke();
+      }
+    }
+
+    // centers are outlined in their district's color, filled so they stand
+    // out from it (and from the blocks under them)
+    context.save();
+    context.set_line_width(3.);
+    for (i, c) in self.solver.centers().iter().enumerate() {
+      let coord = self.to_canvas_coord(canvas, c.coords.into());
+      let color = palette[colors[i] % palette.len()];
+      draw_disc(context, coord, 6., &color.contrast().css(1.).into());
+      draw_circle(context, coord, 6., &color.css(1.).into());
+    }
+    context.restore();
+    Ok(())
+  }
+
+  // The power diagram cell of every center, in block coordinates: a list of
+  // [x, y] points per center (empty if a center is outweighed and has none).
+  // Blocks in a cell are assigned to its center. options: { clip: "hull" or
+  // "bounding_box" }
+  pub fn power_cells(&self, options : JsValue) -> Result<JsValue, JsValue> {
+    let options : CellOptions = if options.is_undefined() || options.is_null() {
+      CellOptions::default()
+    } else {
+      options.into_serde().map_err(|e| JsValue::from_str(&e.to_string()))?
+    };
+    let clip = match options.clip.as_deref() {
+      None | Some("hull") => self.hull.clone(),
+      Some("bounding_box") => power::bounding_box(self.solver.blocks()),
+      Some(_) => return Err("clip should be hull or bounding_box".into()),
+    };
+    let cells = power::power_cells(self.solver.centers(), &clip);
+    to_js(&cells)
+  }
+
+  fn update_colors(&mut self) {
+    if !self.colors_stale {
+      return;
+    }
+    let num_districts = self.solver.centers().len();
+    let adjacency = match &self.block_adjacency {
+      Some(pairs) => {
+        let assignment = self.solver.assignment();
+        coloring::adjacency_from_pairs(num_districts, pairs.iter().map(|&(a, b)| (assignment[a], assignment[b])))
+      },
+      None => coloring::adjacency_from_cells(self.solver.centers(), &self.hull),
+    };
+    self.colors = coloring::dsatur(&adjacency, &self.colors);
+    self.colors_stale = false;
+  }
+
   pub fn draw_centers(&self, context : &web_sys::CanvasRenderingContext2d) {
     let canvas = &context.canvas().unwrap();
-    self.centers.iter().for_each(|c| {
+    self.solver.centers().iter().for_each(|c| {
       let coord = self.to_canvas_coord(canvas, c.coords.into());
       draw_disc(context, coord, 3., &"#cc0000".into());
       draw_circle(context, coord, c.weight, &"#cc0000".into());
@@ -423,6 +962,43 @@ impl Redistricter {
 #[cfg(test)]
 mod tests {
   use super::*;
+
+  // these need js, so run them with `wasm-pack test --node`
+  #[cfg(target_arch = "wasm32")]
+  mod progress {
+    use super::*;
+    use wasm_bindgen_test::*;
+
+    fn grid() -> Redistricter {
+      let xs : Vec<f64> = (0..100).map(|i| (i % 10) as f64).collect();
+      let ys : Vec<f64> = (0..100).map(|i| (i / 10) as f64).collect();
+      let pops = vec![1u32; 100];
+      let mut r = Redistricter::from_arrays(&Float64Array::from(&xs[..]), &Float64Array::from(&ys[..]), &Uint32Array::from(&pops[..])).unwrap();
+      r.configure(JsValue::from_serde(&serde_json::json!({ "num_districts": 4 })).unwrap()).unwrap();
+      r
+    }
+
+    fn cancelled(result : &JsValue) -> bool {
+      js_sys::Reflect::get(result, &"cancelled".into()).unwrap().as_bool().unwrap()
+    }
+
+    #[wasm_bindgen_test]
+    fn test_returning_false_stops_solve() {
+      let mut r = grid();
+      let result = r.solve(1, Some(Function::new_no_args("return false")), None).unwrap();
+      assert!(cancelled(&result));
+      assert_eq!(r.iteration(), 1);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_promises_dont_stop_solve() {
+      // which is what a comlink proxy gives back
+      let mut r = grid();
+      let result = r.solve(1, Some(Function::new_no_args("return Promise.resolve(false)")), None).unwrap();
+      assert!(!cancelled(&result));
+      assert!(r.iteration() > 1);
+    }
+  }
   // use ndarray::*;
   // use ndarray::{Array1, Array2};
   //
@@ -0,0 +1,129 @@
+// The way in for web workers (see src/workers/wasm-worker.js)
+//
+// A Redistricter can't be sent between threads, so the worker keeps them here
+// and hands out session numbers instead. Everything going in or coming out is
+// plain json-able values, so it all goes through comlink as is:
+//
+// * load({ url } or { state_code }) -> { session, meta }
+// * configure(session, { num_districts, algorithm, seed, ... })
+// * step(session, { steps }) -> the last step's diagnostics
+// * solve(session, { every }, progress, cancel) -> { cancelled, progress, report }
+//   (progress can't stop it from here, as comlink makes it return a Promise.
+//   Set cancel[0] instead.)
+// * report(session) -> report
+// * export_plan(session) -> { state_code, config, centers, assignment, report }
+// * close(session)
+//
+// (export is a reserved word in js, hence export_plan.)
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use wasm_bindgen::prelude::*;
+use js_sys::{Function, Int32Array};
+use serde::Deserialize;
+use crate::Redistricter;
+
+thread_local! {
+  static SESSIONS : RefCell<HashMap<u32, Redistricter>> = RefCell::new(HashMap::new());
+  static NEXT_SESSION : Cell<u32> = Cell::new(1);
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Source {
+  Url { url: String },
+  State { state_code: u32 },
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct StepOptions {
+  steps: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct SolveOptions {
+  every: Option<u32>,
+}
+
+// options may be left out altogether
+fn options<T : Default + for<'de> Deserialize<'de>>(value : JsValue) -> Result<T, JsValue> {
+  if value.is_undefined() || value.is_null() {
+    return Ok(T::default());
+  }
+  value.into_serde().map_err(|e| JsValue::from_str(&e.to_string()))
+}
+
+// Run f on a session. It's taken out of the map while f runs, so a progress
+// callback that calls back in gets an error rather than a panic.
+fn with_session<T, F : FnOnce(&mut Redistricter) -> Result<T, JsValue>>(session : u32, f : F) -> Result<T, JsValue> {
+  let mut redistricter = SESSIONS.with(|s| s.borrow_mut().remove(&session))
+    .ok_or_else(|| JsValue::from_str(&format!("No session {} (or it's busy)", session)))?;
+  let result = f(&mut redistricter);
+  SESSIONS.with(|s| s.borrow_mut().insert(session, redistricter));
+  result
+}
+
+#[wasm_bindgen]
+pub async fn load(source : JsValue) -> Result<JsValue, JsValue> {
+  let source : Source = source.into_serde()
+    .map_err(|_| JsValue::from_str("load needs a url or a state_code"))?;
+  let redistricter = match source {
+    Source::Url { url } => Redistricter::from_url(url).await?,
+    Source::State { state_code } => Redistricter::create(state_code).await?,
+  };
+
+  let session = NEXT_SESSION.with(|n| {
+    let session = n.get();
+    n.set(session + 1);
+    session
+  });
+  let meta = redistricter.meta()?;
+  SESSIONS.with(|s| s.borrow_mut().insert(session, redistricter));
+
+  let loaded = js_sys::Object::new();
+  js_sys::Reflect::set(&loaded, &"session".into(), &session.into())?;
+  js_sys::Reflect::set(&loaded, &"meta".into(), &meta)?;
+  Ok(loaded.into())
+}
+
+#[wasm_bindgen]
+pub fn configure(session : u32, config : JsValue) -> Result<(), JsValue> {
+  with_session(session, |r| r.configure(config))
+}
+
+#[wasm_bindgen]
+pub fn step(session : u32, options : JsValue) -> Result<JsValue, JsValue> {
+  let StepOptions { steps } = self::options(options)?;
+  with_session(session, |r| {
+    let mut last = JsValue::NULL;
+    for _ in 0..steps.unwrap_or(1) {
+      if r.is_converged() { break; }
+      last = r.step()?;
+    }
+    Ok(last)
+  })
+}
+
+#[wasm_bindgen]
+pub fn solve(session : u32, options : JsValue, progress : Option<Function>, cancel : Option<Int32Array>) -> Result<JsValue, JsValue> {
+  let SolveOptions { every } = self::options(options)?;
+  with_session(session, |r| r.solve(every.unwrap_or(1), progress, cancel))
+}
+
+#[wasm_bindgen]
+pub fn report(session : u32) -> Result<JsValue, JsValue> {
+  with_session(session, |r| r.report())
+}
+
+#[wasm_bindgen]
+pub fn export_plan(session : u32) -> Result<JsValue, JsValue> {
+  with_session(session, |r| r.export())
+}
+
+// Forget a session and free its blocks
+#[wasm_bindgen]
+pub fn close(session : u32) -> bool {
+  SESSIONS.with(|s| s.borrow_mut().remove(&session)).is_some()
+}
@@ -1,6 +1,22 @@
+import { transferHandlers } from 'comlink'
+
 const app = import('@/wasm/pkg/app')
 app.then( mod => mod.browser_debug() )
 
+// comlink only sends an error's message and stack to the main thread. Load
+// errors carry a kind (and details like status) as extra fields, so send
+// those too. The main thread's side copies them onto the error it throws.
+const throwHandler = transferHandlers.get('throw')
+transferHandlers.set('throw', Object.assign({}, throwHandler, {
+  serialize({ value }){
+    const [serialized, transferables] = throwHandler.serialize({ value })
+    if ( serialized.isError ){
+      serialized.value = Object.assign({}, value, serialized.value)
+    }
+    return [serialized, transferables]
+  }
+}))
+
 // Helpers
 // ---------------------------------------
 function log( ...args ){
@@ -25,10 +41,11 @@ async function run( method, ...args ){
 
   try {
     log(`Worker: Running ${method}`, args)
-    return fn.apply( wasm, args )
-  } catch( msg ){
-    // wasm bingen doesn't throw true js errors....
-    let err = new Error( msg )
+    return await fn.apply( wasm, args )
+  } catch( e ){
+    // errors made on the wasm side (like load errors) are real js errors
+    // already, but plain strings come through too
+    let err = e instanceof Error ? e : new Error( e )
     logErr(`Worker: ERROR from ${method}`, err)
     throw err
   }
@@ -36,6 +53,40 @@ async function run( method, ...args ){
 
 // API
 // ---------------------------------------
-export async function test( cfg ){
-  return run('test', cfg)
+// Each load makes a session on the wasm side. Pass its number to the rest.
+
+// { url } or { state_code }. Resolves to { session, meta }
+export async function load( source ){
+  return run('load', source)
+}
+
+// { num_districts, algorithm, seed, max_iterations, tolerance }
+export async function configure( session, cfg ){
+  return run('configure', session, cfg)
+}
+
+// { steps }. Resolves to the last step's diagnostics
+export async function step( session, opts ){
+  return run('step', session, opts)
+}
+
+// { every }. progress (wrapped in comlink's proxy) gets the diagnostics every
+// `every` iterations. Through the proxy its return value is a Promise, so it
+// can't stop the solve. cancel is an Int32Array over a SharedArrayBuffer: set
+// cancel[0] to 1 from the main thread to stop early.
+export async function solve( session, opts, progress, cancel ){
+  return run('solve', session, opts, progress, cancel)
+}
+
+export async function report( session ){
+  return run('report', session)
+}
+
+// can't be called export
+export async function exportPlan( session ){
+  return run('export_plan', session)
+}
+
+export async function close( session ){
+  return run('close', session)
 }